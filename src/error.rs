@@ -0,0 +1,113 @@
+//! The error type of the text processor.
+use proc_macro2::{LineColumn, Span};
+use std::fmt;
+
+/// An error that stops the processing of a doc comment.
+#[derive(Debug)]
+pub enum SvgbobdocError {
+    /// A diagram's code block isn't closed by the end of the doc comment.
+    UnclosedCodeBlock { span: Span },
+    /// A code fence is malformed.
+    InvalidFence { span: Span, message: String },
+    /// The options in a code fence header are invalid.
+    InvalidOptions { span: Span, message: String },
+    /// A diagram couldn't be converted or has a potential problem, and
+    /// `strict-render` is enabled.
+    Render { span: Span, message: String },
+}
+
+impl SvgbobdocError {
+    /// Get the location of the error.
+    pub fn span(&self) -> Span {
+        match self {
+            Self::UnclosedCodeBlock { span }
+            | Self::InvalidFence { span, .. }
+            | Self::InvalidOptions { span, .. }
+            | Self::Render { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for SvgbobdocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnclosedCodeBlock { .. } => f.write_str("unclosed code block"),
+            Self::InvalidFence { message, .. }
+            | Self::InvalidOptions { message, .. }
+            | Self::Render { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for SvgbobdocError {}
+
+impl From<SvgbobdocError> for syn::Error {
+    fn from(e: SvgbobdocError) -> Self {
+        syn::Error::new(e.span(), e)
+    }
+}
+
+/// Format a non-fatal diagnostic with the location of its span, `start`, for
+/// the standard error.
+///
+/// The path of the source file isn't available to procedural macros, so the
+/// location is given by the crate being compiled (`crate_name`) and the line
+/// and column. The compiler only provides line numbers on some toolchains;
+/// without them (`start.line == 0`), the location is omitted.
+pub fn format_warning(
+    message: &dyn fmt::Display,
+    start: LineColumn,
+    crate_name: Option<&str>,
+) -> String {
+    let mut text = format!("warning: {}", message);
+    if start.line != 0 {
+        text += &format!("\n  --> line {}, column {}", start.line, start.column + 1);
+        if let Some(crate_name) = crate_name {
+            text += &format!(" of crate `{}`", crate_name);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn into_syn_error() {
+        let span = Span::call_site();
+        let e: syn::Error = SvgbobdocError::UnclosedCodeBlock { span }.into();
+        assert_eq!(e.to_string(), "unclosed code block");
+        let e: syn::Error = SvgbobdocError::InvalidOptions {
+            span,
+            message: "unknown option `foo`".to_owned(),
+        }
+        .into();
+        assert_eq!(e.to_string(), "unknown option `foo`");
+    }
+
+    #[test]
+    fn warning_location() {
+        let start = LineColumn {
+            line: 12,
+            column: 4,
+        };
+        assert_eq!(
+            format_warning(&"tab characters", start, Some("foo")),
+            "warning: tab characters\n  --> line 12, column 5 of crate `foo`"
+        );
+        assert_eq!(
+            format_warning(&"tab characters", start, None),
+            "warning: tab characters\n  --> line 12, column 5"
+        );
+    }
+
+    #[test]
+    fn warning_without_location() {
+        let start = LineColumn { line: 0, column: 0 };
+        assert_eq!(
+            format_warning(&"tab characters", start, Some("foo")),
+            "warning: tab characters"
+        );
+    }
+}
@@ -5,9 +5,12 @@ use quote::{quote, ToTokens};
 use syn::{
     self,
     parse::{Parse, ParseStream},
-    parse_macro_input, AttrStyle, Attribute, Error, Lit, LitStr, Meta, MetaNameValue, Result,
+    parse_macro_input, AttrStyle, Attribute, Error, Ident, Lit, LitStr, Meta, MetaNameValue,
+    Result, Token,
 };
 
+mod error;
+mod options;
 mod textproc;
 
 /// An `Attribute`, recognized as a doc comment or not.
@@ -24,8 +27,9 @@ enum MaybeDocAttr {
 }
 
 impl MaybeDocAttr {
-    fn from_attribute(attr: Attribute) -> Result<Self> {
-        if attr.path.is_ident("doc") {
+    /// Recognize `attr` as a doc comment if it's named `doc` or `doc_attr`.
+    fn from_attribute(attr: Attribute, doc_attr: Option<&str>) -> Result<Self> {
+        if attr.path.is_ident("doc") || doc_attr.map_or(false, |name| attr.path.is_ident(name)) {
             let meta = attr.parse_meta()?;
 
             if let Meta::NameValue(nv) = meta {
@@ -75,6 +79,65 @@ impl Into<Attribute> for MaybeDocAttr {
     }
 }
 
+/// The environment variable specifying the default diagram options for all
+/// `transform!` invocations.
+const OPTIONS_ENV_VAR: &str = "SVGBOBDOC_OPTIONS";
+
+/// The input to `transform!`.
+struct TransformInput {
+    /// The diagram options preceding the doc comment (`key = value,`).
+    options: Vec<(Ident, Lit)>,
+    body: StrOrDocAttrs,
+}
+
+impl Parse for TransformInput {
+    fn parse(input: ParseStream<'_>) -> Result<Self> {
+        let mut options = Vec::new();
+        while input.peek(Ident) && input.peek2(Token![=]) {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value: Lit = input.parse()?;
+            input.parse::<Token![,]>()?;
+            options.push((key, value));
+        }
+
+        Ok(Self {
+            options,
+            body: input.parse()?,
+        })
+    }
+}
+
+impl TransformInput {
+    /// Convert `self.options` to `DiagramOptions`.
+    fn diagram_options(&self) -> Result<options::DiagramOptions> {
+        let mut diagram_options = options::DiagramOptions::default();
+        if let Ok(list) = std::env::var(OPTIONS_ENV_VAR) {
+            diagram_options.set_list(&list).map_err(|e| {
+                Error::new(
+                    Span::call_site(),
+                    format!("invalid `{}`: {}", OPTIONS_ENV_VAR, e),
+                )
+            })?;
+        }
+        for (key, value) in self.options.iter() {
+            let value = match value {
+                Lit::Str(x) => x.value(),
+                Lit::Int(x) => x.base10_digits().to_owned(),
+                Lit::Float(x) => x.base10_digits().to_owned(),
+                Lit::Bool(x) => x.value.to_string(),
+                _ => return Err(Error::new(value.span(), "unsupported literal")),
+            };
+            // Option names use `-` in code fences
+            let key_str = key.to_string().replace('_', "-");
+            diagram_options
+                .set(&key_str, Some(&value))
+                .map_err(|e| Error::new(key.span(), e))?;
+        }
+        Ok(diagram_options)
+    }
+}
+
 enum StrOrDocAttrs {
     Str(LitStr),
     Attrs(Vec<syn::Attribute>),
@@ -96,20 +159,29 @@ impl Parse for StrOrDocAttrs {
 /// Render ASCII-diagram code blocks in a Markdown-formatted string literal or
 /// zero or more `#[doc = ...]` attributes as SVG images.
 ///
+/// The input can be preceded by diagram options (`key = value,`), which apply
+/// to all code blocks unless overridden by a code fence header. `_` in the
+/// option names is read as `-`. The defaults of these options can be specified
+/// by the `SVGBOBDOC_OPTIONS` environment variable (`key=value,...`).
+///
 /// See [the module-level documentation](../index.html) for more.
 #[proc_macro]
 pub fn transform(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let input: StrOrDocAttrs = parse_macro_input!(tokens);
+    let input: TransformInput = parse_macro_input!(tokens);
+    let defaults = match input.diagram_options() {
+        Ok(x) => x,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let doc_attr = defaults.doc_attr.clone();
     let (mut iter1, mut iter2);
-    let iter: &mut dyn Iterator<Item = Result<LitStr>> = match input {
+    let iter: &mut dyn Iterator<Item = Result<LitStr>> = match input.body {
         StrOrDocAttrs::Str(s) => {
             iter1 = std::iter::once(Ok(s));
             &mut iter1
         }
         StrOrDocAttrs::Attrs(attrs) => {
-            iter2 = attrs
-                .into_iter()
-                .map(|attr| match MaybeDocAttr::from_attribute(attr)? {
+            iter2 = attrs.into_iter().map(move |attr| {
+                match MaybeDocAttr::from_attribute(attr, doc_attr.as_deref())? {
                     MaybeDocAttr::Doc(
                         _,
                         syn::MetaNameValue {
@@ -123,26 +195,20 @@ pub fn transform(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
                             "only `#[doc = ...]` attributes or a string literal are allowed here",
                         ))
                     }
-                });
+                }
+            });
             &mut iter2
         }
     };
 
     handle_error(|| {
-        let mut output = String::new();
-        use textproc::{TextProcOutput, TextProcState};
-        let mut text_proc = TextProcState::new();
-        for lit_str in iter {
-            let lit_str = lit_str?;
-            let st = lit_str.value();
-            match text_proc.step(&st, lit_str.span()) {
-                TextProcOutput::Passthrough => output.push_str(&st),
-                TextProcOutput::Fragment(fr) => output.push_str(&fr),
-                TextProcOutput::Empty => {}
-            }
-            output.push_str("\n");
+        let fragments = iter
+            .map(|lit_str| lit_str.map(|lit_str| (lit_str.value(), lit_str.span())))
+            .collect::<Result<Vec<_>>>()?;
+        let (output, warnings) = textproc::transform_fragments(defaults, fragments)?;
+        for warning in &warnings {
+            emit_warning(warning);
         }
-        text_proc.finalize()?;
 
         Ok(LitStr::new(&output, Span::call_site())
             .into_token_stream()
@@ -150,9 +216,55 @@ pub fn transform(tokens: proc_macro::TokenStream) -> proc_macro::TokenStream {
     })
 }
 
+/// Report a non-fatal diagnostic at the start of its span.
+///
+/// Procedural macros can't emit warnings on stable Rust, so the message is
+/// written to the standard error instead, which Cargo displays for local
+/// packages.
+fn emit_warning(warning: &Error) {
+    let crate_name = std::env::var("CARGO_CRATE_NAME").ok();
+    eprintln!(
+        "{}",
+        error::format_warning(warning, warning.span().start(), crate_name.as_deref())
+    );
+}
+
 fn handle_error(cb: impl FnOnce() -> Result<proc_macro::TokenStream>) -> proc_macro::TokenStream {
     match cb() {
         Ok(tokens) => tokens,
         Err(e) => e.to_compile_error().into(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doc_attr() {
+        let input: TransformInput = syn::parse_quote! {
+            doc_attr = "my_doc",
+            #[my_doc = "text"]
+            #[doc = "text"]
+            #[other]
+        };
+        let doc_attr = input.diagram_options().unwrap().doc_attr;
+        assert_eq!(doc_attr.as_deref(), Some("my_doc"));
+        let attrs = match input.body {
+            StrOrDocAttrs::Attrs(attrs) => attrs,
+            StrOrDocAttrs::Str(_) => panic!("parsed as a string literal"),
+        };
+
+        let is_doc = |doc_attr: Option<&str>| -> Vec<bool> {
+            attrs
+                .iter()
+                .map(|attr| {
+                    let attr = MaybeDocAttr::from_attribute(attr.clone(), doc_attr).unwrap();
+                    matches!(attr, MaybeDocAttr::Doc(..))
+                })
+                .collect()
+        };
+        assert_eq!(is_doc(doc_attr.as_deref()), [true, true, false]);
+        assert_eq!(is_doc(None), [false, true, false]);
+    }
+}
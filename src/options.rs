@@ -0,0 +1,920 @@
+//! Diagram options, which are specified in a code fence header
+//! (`~~~svgbob,key=value`) or as an argument of `transform!` (`key = value`).
+
+/// Define `DiagramOptions` and `OPTION_NAMES` from a single list so that they
+/// can't go out of sync.
+macro_rules! define_options {
+    ($(
+        $(#[$meta:meta])*
+        $name:literal => $field:ident: $ty:ty,
+    )*) => {
+        /// The options controlling how a diagram is rendered and emitted.
+        #[derive(Debug, Clone, Default)]
+        pub struct DiagramOptions {
+            $(
+                $(#[$meta])*
+                pub $field: $ty,
+            )*
+        }
+
+        /// The names of all recognized options.
+        pub const OPTION_NAMES: &[&str] = &[$($name),*];
+
+        impl DiagramOptions {
+            /// Set the option named `key`. `value` is `None` if the option
+            /// was specified without a value (`key` instead of `key=value`).
+            pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+                match key {
+                    $($name => self.$field = OptionValue::parse_option(key, value)?,)*
+                    _ => {
+                        return Err(format!(
+                            "unknown option `{}`; the recognized options are: {}",
+                            key,
+                            OPTION_NAMES.join(", "),
+                        ))
+                    }
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+define_options! {
+    /// The directory to write images to, relative to the target directory.
+    /// If this is `None`, images are embedded as data URIs.
+    "out-dir" => out_dir: Option<String>,
+    /// The prefix of the URLs referencing the images written to `out_dir`.
+    /// Defaults to the `file:` URL of `out_dir`.
+    "url-prefix" => url_prefix: Option<String>,
+    /// The maximum length of a data URI that is embedded even if `out_dir`
+    /// is set.
+    "inline-limit" => inline_limit: usize,
+    /// Escape the characters that need escaping in a Rust string literal
+    /// (`\` and `"`) in the generated Markdown text.
+    "literal-safe" => literal_safe: bool,
+    /// The size of a character cell in the horizontal direction, measured in
+    /// pixels. A cell is twice as tall as it is wide. Defaults to svgbob's
+    /// default (8).
+    "scale" => scale: Option<f32>,
+    /// Find code blocks in block quotes (`> ~~~svgbob`) and remove the block
+    /// quote markers from their lines. Only effective in the defaults.
+    "blockquote" => blockquote: bool,
+    /// Report potential problems in the diagram as errors instead of
+    /// warnings, and fail instead of emitting an error message if the diagram
+    /// can't be converted.
+    "strict-render" => strict_render: bool,
+    /// Draw a border around the image.
+    "border" => border: bool,
+    /// The `font-weight` of texts.
+    "font-weight" => font_weight: Option<Keyword>,
+    /// The font preferred over the default font stack.
+    "font-primary" => font_primary: Option<FontName>,
+    /// The unit of the image's width and height.
+    "unit" => unit: Unit,
+    /// The number of pixels per inch used for converting the image's size to
+    /// `unit=mm` or `unit=in`. Defaults to the resolution of CSS pixels (96).
+    "dpi" => dpi: Option<f32>,
+    /// The alternative text of the image.
+    "alt" => alt: Option<String>,
+    /// The template of the alternative text used when `alt` is not given.
+    /// `{n}` is replaced with the 1-based index of the diagram in the doc
+    /// comment.
+    "alt-template" => alt_template: Option<String>,
+    /// The text prepended to the alternative text unless it's empty.
+    "alt-prefix" => alt_prefix: Option<String>,
+    /// How images are embedded in the generated Markdown text.
+    "encoding" => encoding: Encoding,
+    /// Emit the diagram as ASCII art in a `<pre>` element instead of rendering
+    /// it.
+    "ascii" => ascii: bool,
+    /// The number of decimal places of `textLength` attributes. Defaults to
+    /// the shortest representation of the exact value.
+    "text-length-precision" => text_length_precision: Option<usize>,
+    /// Overlay the character cell grid on the image. This is an authoring
+    /// aid for aligning the art.
+    "debug-grid" => debug_grid: bool,
+    /// Emit Markdown images as reference links (`![][svgbob-diagram-HASH]`)
+    /// whose definitions are placed at the end of the doc comment. The labels
+    /// are derived from the images.
+    "reference-links" => reference_links: bool,
+    /// Emit the image as an `<object>` element, in which scripts in the SVG
+    /// code are executed.
+    "object" => object: bool,
+    /// Remove the trailing blank lines of the diagram, which would otherwise
+    /// be rendered as empty rows.
+    "trim-blank-lines" => trim_blank_lines: bool,
+    /// Keep the line break at the end of the diagram so that a trailing blank
+    /// line is rendered as an empty row.
+    "keep-trailing-newline" => keep_trailing_newline: bool,
+    /// The accessible name of the image, set as the `aria-label` attribute of
+    /// the root element along with `role="img"`.
+    "a11y" => a11y: Option<String>,
+    /// Define the diagram as a `<symbol>` element with this ID so that later
+    /// diagrams can reference it by `ref`. Requires `encoding=inline-svg`.
+    "id" => id: Option<Keyword>,
+    /// Display the diagram defined by `id` instead of the content. Requires
+    /// `encoding=inline-svg`.
+    "ref" => ref_id: Option<Keyword>,
+    /// Convert CRLF and CR line endings in the doc comment to LF. Only
+    /// effective as an argument of `transform!` or in `SVGBOBDOC_OPTIONS`.
+    "normalize-line-endings" => normalize_line_endings: bool,
+    /// Remove the leading whitespace common to all lines of the doc comment
+    /// before finding code blocks. Only effective as an argument of
+    /// `transform!` or in `SVGBOBDOC_OPTIONS`.
+    "dedent" => dedent: bool,
+    /// The character recognized as a code fence marker in addition to `` ` ``
+    /// and `~` (e.g., `:` for `:::svgbob`). Only effective as an argument of
+    /// `transform!` or in `SVGBOBDOC_OPTIONS`.
+    "fence-char" => fence_char: Option<FenceChar>,
+    /// The name of an attribute accepted in place of `doc` in the input of
+    /// `transform!` (e.g., `my_doc` for `#[my_doc = "..."]`). Only effective
+    /// as an argument of `transform!` or in `SVGBOBDOC_OPTIONS`.
+    "doc-attr" => doc_attr: Option<String>,
+    /// The aspect ratio of the image. The diagram is centered in the image.
+    "aspect" => aspect: Option<AspectRatio>,
+    /// The file to read the diagram from, relative to `CARGO_MANIFEST_DIR`.
+    /// The content of the code block is ignored.
+    "src" => src: Option<String>,
+    /// Overlay the art as transparent texts on the image so that it can be
+    /// selected and copied. Only effective with `encoding=inline-svg`.
+    "selectable" => selectable: bool,
+    /// The `stroke-linecap` of lines and shapes.
+    "stroke-linecap" => stroke_linecap: Option<Keyword>,
+    /// The `stroke-linejoin` of lines and shapes.
+    "stroke-linejoin" => stroke_linejoin: Option<Keyword>,
+    /// Set the CSS custom properties `--dw` and `--dh` of the root element to
+    /// the diagram's intrinsic width and height.
+    "size-properties" => size_properties: bool,
+    /// The set of layout fixes applied on top of svgbob.
+    "compat" => compat: Compat,
+    /// The CSS `margin` of a `<div>` wrapping the image (e.g., `1em 0`).
+    "margin" => margin: Option<String>,
+    /// The CSS `image-rendering` of the image (e.g., `crisp-edges`,
+    /// `pixelated`).
+    "image-rendering" => image_rendering: Option<Keyword>,
+    /// Render the diagram to check for problems, but emit it as ASCII art
+    /// instead of an image. This keeps the output small, e.g., in CI.
+    "dry-run" => dry_run: bool,
+    /// Remove the diagram from the output altogether without rendering it,
+    /// e.g., for quick previews of the documentation.
+    "strip" => strip: bool,
+    /// The alignment of texts relative to the character cells they span.
+    /// Defaults to svgbob's (`start`).
+    "text-anchor" => text_anchor: Option<TextAnchor>,
+    /// Write the image to `out_dir` regardless of `inline_limit` and emit a
+    /// Markdown link to it instead of the image.
+    "link-svg" => link_svg: bool,
+    /// How the widths of texts are measured for `textLength`.
+    "width-metric" => width_metric: WidthMetric,
+    /// Wrap the image in a horizontally scrollable `<div>` and keep it at its
+    /// natural size.
+    "scroll" => scroll: bool,
+    /// Warn if the image is wider than this many pixels, unless `scroll` is
+    /// set. `0` disables the warning. Defaults to the width of rustdoc's
+    /// content column (960).
+    "warn-width" => warn_width: Option<usize>,
+    /// Draw a rounded rectangle behind the diagram, extending the image by a
+    /// padding.
+    "card" => card: bool,
+    /// Follow the SVG markup with an equivalent image that replaces it if
+    /// scripts are disabled. Requires `encoding=inline-svg`.
+    "fallback" => fallback: bool,
+    /// The color preset of the diagram.
+    "theme" => theme: Option<Theme>,
+    /// Custom color presets selectable by `theme`.
+    "theme-presets" => theme_presets: Option<ThemePresets>,
+    /// Fill the filled shapes with a vertical linear gradient between two
+    /// colors (`from,to`).
+    "fill-gradient" => fill_gradient: Option<Gradient>,
+    /// Wrap the image in a `<figure>` captioned "Figure N.", where `N` counts
+    /// the diagrams with this option in the doc comment.
+    "figure" => figure: bool,
+    /// Replace the distinct colors of the image with the colors of a palette.
+    "palette" => palette: Option<Palette>,
+    /// The language of the texts, set as the `lang` and `xml:lang` attributes
+    /// of the root element (e.g., `fr`). Not to be confused with the
+    /// `lang:NAME` hint.
+    "xml-lang" => xml_lang: Option<Keyword>,
+    /// Measure the time taken to convert the diagram and report the slowest
+    /// diagrams in the doc comment as a warning.
+    "timings" => timings: bool,
+    /// Follow the image with the art in a collapsed `<details>` element.
+    "with-source" => with_source: bool,
+    /// The bounds of the image.
+    "fit" => fit: Fit,
+    /// The width of the image as a percentage of the containing block. The
+    /// height follows the aspect ratio unless `height` is given.
+    "width" => width: Option<Percentage>,
+    /// The height of the image as a percentage of the containing block. The
+    /// width follows the aspect ratio unless `width` is given.
+    "height" => height: Option<Percentage>,
+    /// Write the art to `out_dir` and emit a marker comment referencing it
+    /// instead of rendering the diagram, so that a later pass can substitute
+    /// the image. Requires `out_dir`.
+    "defer" => defer: bool,
+    /// Warn about code blocks whose languages look like misspellings of
+    /// `svgbob`. Only effective as an argument of `transform!` or in
+    /// `SVGBOBDOC_OPTIONS`.
+    "audit" => audit: bool,
+    /// Make the diagrams with the same group name as wide as the widest of
+    /// them.
+    "group" => group: Option<Keyword>,
+    /// Cast a drop shadow from the lines, shapes, and texts.
+    "shadow" => shadow: bool,
+}
+
+impl DiagramOptions {
+    /// Set the options in a comma-separated list of `key=value` or `key`.
+    pub fn set_list(&mut self, s: &str) -> Result<(), String> {
+        for part in split_list(s) {
+            self.set_part(part)?;
+        }
+        Ok(())
+    }
+
+    /// Set the option specified in the form of `key=value`, `key="value"`,
+    /// or `key`.
+    pub fn set_part(&mut self, part: &str) -> Result<(), String> {
+        let (key, value) = split_part(part);
+        if value.is_none() && key.bytes().all(|b| b.is_ascii_digit()) {
+            // Probably the fractional part of `key=1,5`
+            return Err(format!(
+                "unexpected number `{}` after `,`; decimal numbers must use `.` \
+                 (e.g., `1.5`) because `,` separates options",
+                key
+            ));
+        }
+        let value = value.map(|value| {
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value)
+        });
+        self.set(key, value)
+    }
+}
+
+/// Split an option specified in the form of `key=value` or `key` into the key
+/// and the value.
+pub fn split_part(part: &str) -> (&str, Option<&str>) {
+    match part.find('=') {
+        Some(i) => (part[..i].trim_end(), Some(part[i + 1..].trim_start())),
+        None => (part, None),
+    }
+}
+
+/// Split a comma-separated list of options, skipping empty elements. Commas
+/// in quoted values (`key="a, b"`) don't split elements, and neither do commas
+/// followed by `#` (`key=#aaa,#333`), which can't start an option name.
+pub fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || loop {
+        if rest.is_empty() {
+            return None;
+        }
+
+        // A quoted value starts with `="` and ends with the next `"`
+        let mut quoted = false;
+        let mut last = '\0';
+        let end = rest
+            .char_indices()
+            .find(|&(i, ch)| {
+                if ch == '"' && (quoted || last == '=') {
+                    quoted = !quoted;
+                }
+                last = ch;
+                ch == ',' && !quoted && !rest[i + 1..].trim_start().starts_with('#')
+            })
+            .map_or(rest.len(), |(i, _)| i);
+
+        let part = rest[..end].trim();
+        rest = rest.get(end + 1..).unwrap_or("");
+        if !part.is_empty() {
+            return Some(part);
+        }
+    })
+}
+
+/// A CSS keyword or number (e.g., `bold`, `600`), which can be placed in an
+/// attribute value without escaping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyword(pub String);
+
+impl std::str::FromStr for Keyword {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.'))
+        {
+            Ok(Self(s.to_owned()))
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// A font family name (e.g., `My Mono`), which can be quoted in CSS without
+/// escaping.
+#[derive(Debug, Clone)]
+pub struct FontName(pub String);
+
+impl std::str::FromStr for FontName {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.trim().is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b' ' | b'-' | b'_' | b'.'))
+        {
+            Ok(Self(s.to_owned()))
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// The unit of an image's dimensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    /// Pixels.
+    Px,
+    /// The font size of the containing page. The image's coordinate system is
+    /// preserved by `viewBox`.
+    Em,
+    /// Millimeters, converted from pixels by `dpi`. Like `Em`, the image's
+    /// coordinate system is preserved.
+    Mm,
+    /// Inches, converted from pixels by `dpi`.
+    In,
+}
+
+impl Default for Unit {
+    fn default() -> Self {
+        Self::Px
+    }
+}
+
+impl std::str::FromStr for Unit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "px" => Ok(Self::Px),
+            "em" => Ok(Self::Em),
+            "mm" => Ok(Self::Mm),
+            "in" => Ok(Self::In),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An ASCII punctuation character that can mark a code fence (e.g., `:`).
+/// `>` is excluded because it starts a block quote.
+#[derive(Debug, Clone, Copy)]
+pub struct FenceChar(pub u8);
+
+impl std::str::FromStr for FenceChar {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match *s.as_bytes() {
+            [b] if b.is_ascii_punctuation() && b != b'>' => Ok(Self(b)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An aspect ratio in the form of `width:height` (e.g., `16:9`).
+#[derive(Debug, Clone, Copy)]
+pub struct AspectRatio {
+    pub width: f32,
+    pub height: f32,
+}
+
+impl std::str::FromStr for AspectRatio {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s.find(':').ok_or(())?;
+        let width: f32 = s[..i].trim().parse().map_err(|_| ())?;
+        let height: f32 = s[i + 1..].trim().parse().map_err(|_| ())?;
+        if width > 0.0 && height > 0.0 && (width / height).is_finite() {
+            Ok(Self { width, height })
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// A set of layout fixes applied on top of svgbob. Pinning one keeps the
+/// output stable when the fixes change.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Compat {
+    /// svgbob's own layout.
+    V1,
+    /// Texts are stretched by `textLength` to align with the character cells,
+    /// and the image height is fixed to match the number of lines.
+    V2,
+}
+
+impl Default for Compat {
+    fn default() -> Self {
+        Self::V2
+    }
+}
+
+impl std::str::FromStr for Compat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Self::V1),
+            "2" => Ok(Self::V2),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The `text-anchor` of texts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextAnchor {
+    /// Texts are aligned to the left edges of their cells.
+    Start,
+    /// Texts are centered in their cells.
+    Middle,
+    /// Texts are aligned to the right edges of their cells.
+    End,
+}
+
+impl TextAnchor {
+    /// The value of the `text-anchor` attribute.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::Middle => "middle",
+            Self::End => "end",
+        }
+    }
+
+    /// The distance from the start of a text of width `width` to its anchor
+    /// point.
+    pub fn offset(self, width: f32) -> f32 {
+        match self {
+            Self::Start => 0.0,
+            Self::Middle => width / 2.0,
+            Self::End => width,
+        }
+    }
+}
+
+impl std::str::FromStr for TextAnchor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(Self::Start),
+            "middle" => Ok(Self::Middle),
+            "end" => Ok(Self::End),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the width of a text is measured in character cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WidthMetric {
+    /// The display width defined by Unicode, in which East Asian wide
+    /// characters occupy two cells.
+    Unicode,
+    /// The number of characters.
+    Chars,
+    /// The number of bytes in UTF-8.
+    Bytes,
+}
+
+impl Default for WidthMetric {
+    fn default() -> Self {
+        Self::Unicode
+    }
+}
+
+impl std::str::FromStr for WidthMetric {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unicode" => Ok(Self::Unicode),
+            "chars" => Ok(Self::Chars),
+            "bytes" => Ok(Self::Bytes),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A color preset.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Theme {
+    /// Light texts and lines on a dark background, after the Monokai color
+    /// scheme.
+    Monokai,
+    /// Dark texts and lines on a light background, after the light variant of
+    /// the Solarized color scheme.
+    Solarized,
+    /// A preset defined by `theme-presets`.
+    Custom(Keyword),
+}
+
+/// The colors of a `Theme`, which can be placed in a stylesheet without
+/// escaping.
+#[derive(Debug, Clone)]
+pub struct ThemeColors {
+    /// The color of lines and filled shapes.
+    pub stroke: String,
+    /// The color of texts.
+    pub text: String,
+    /// The color of the background.
+    pub background: String,
+}
+
+impl Theme {
+    /// Get the colors of the preset. Returns `None` if a custom preset isn't
+    /// defined by `presets`.
+    pub fn colors(&self, presets: Option<&ThemePresets>) -> Option<ThemeColors> {
+        let builtin = |stroke: &str, text: &str, background: &str| ThemeColors {
+            stroke: stroke.to_owned(),
+            text: text.to_owned(),
+            background: background.to_owned(),
+        };
+        match self {
+            Self::Monokai => Some(builtin("#66d9ef", "#f8f8f2", "#272822")),
+            Self::Solarized => Some(builtin("#586e75", "#657b83", "#fdf6e3")),
+            Self::Custom(name) => presets?
+                .0
+                .iter()
+                .find(|(preset_name, _)| preset_name.0 == name.0)
+                .map(|(_, colors)| colors.clone()),
+        }
+    }
+}
+
+impl std::str::FromStr for Theme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "monokai" => Ok(Self::Monokai),
+            "solarized" => Ok(Self::Solarized),
+            _ => s.parse().map(Self::Custom),
+        }
+    }
+}
+
+/// Custom color presets, each given as `NAME:STROKE,TEXT,BACKGROUND` and
+/// separated by whitespace (e.g., `dusk:#8be9fd,#f8f8f2,#282a36`). The value
+/// must be quoted in a list of options unless `TEXT` and `BACKGROUND` start
+/// with `#`.
+#[derive(Debug, Clone)]
+pub struct ThemePresets(pub Vec<(Keyword, ThemeColors)>);
+
+impl std::str::FromStr for ThemePresets {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn is_color(s: &str) -> bool {
+            !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'#')
+        }
+
+        let mut presets = Vec::new();
+        for preset in s.split_whitespace() {
+            let i = preset.find(':').ok_or(())?;
+            let name: Keyword = preset[..i].parse()?;
+            if name.0 == "monokai" || name.0 == "solarized" {
+                // Would be shadowed by the built-in preset
+                return Err(());
+            }
+            let colors: Vec<&str> = preset[i + 1..].split(',').collect();
+            match colors[..] {
+                [stroke, text, background] if colors.iter().all(|c| is_color(c)) => {
+                    presets.push((
+                        name,
+                        ThemeColors {
+                            stroke: stroke.to_owned(),
+                            text: text.to_owned(),
+                            background: background.to_owned(),
+                        },
+                    ));
+                }
+                _ => return Err(()),
+            }
+        }
+        Ok(Self(presets))
+    }
+}
+
+/// A non-negative percentage (e.g., `100%`).
+#[derive(Debug, Clone, Copy)]
+pub struct Percentage(pub f32);
+
+impl std::str::FromStr for Percentage {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value: f32 = s.strip_suffix('%').ok_or(())?.parse().map_err(|_| ())?;
+        if value >= 0.0 && value.is_finite() {
+            Ok(Self(value))
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// The bounds of an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// The whole character grid of the diagram.
+    Grid,
+    /// The bounding box of the drawn shapes and texts, which excludes the
+    /// blank margins.
+    Tight,
+}
+
+impl Default for Fit {
+    fn default() -> Self {
+        Self::Grid
+    }
+}
+
+impl std::str::FromStr for Fit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grid" => Ok(Self::Grid),
+            "tight" => Ok(Self::Tight),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A set of colors replacing those of an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Palette {
+    /// The palette by Okabe and Ito, whose colors can be told apart with any
+    /// of the common types of color blindness.
+    ColorblindSafe,
+}
+
+impl Palette {
+    pub fn colors(self) -> &'static [&'static str] {
+        match self {
+            Self::ColorblindSafe => &[
+                "#0072b2", "#e69f00", "#009e73", "#cc79a7", "#56b4e9", "#d55e00", "#f0e442",
+            ],
+        }
+    }
+}
+
+impl std::str::FromStr for Palette {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "colorblind-safe" => Ok(Self::ColorblindSafe),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A vertical linear gradient between two colors in the form of `from,to`
+/// (e.g., `#aaa,#333`). The colors can be placed in an attribute value
+/// without escaping. The value must be quoted in a list of options unless
+/// `to` starts with `#`.
+#[derive(Debug, Clone)]
+pub struct Gradient {
+    pub from: String,
+    pub to: String,
+}
+
+impl std::str::FromStr for Gradient {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn is_color(s: &str) -> bool {
+            !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'#')
+        }
+
+        let i = s.find(',').ok_or(())?;
+        let (from, to) = (s[..i].trim(), s[i + 1..].trim());
+        if is_color(from) && is_color(to) {
+            Ok(Self {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            })
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// How an image is embedded in Markdown text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// A base64-encoded data URI.
+    Base64,
+    /// A percent-encoded data URI, which is smaller than `Base64` for most
+    /// diagrams.
+    Percent,
+    /// `Base64` or `Percent`, whichever is smaller for the image.
+    Auto,
+    /// SVG markup in an HTML block. Unlike images, the markup can access the
+    /// containing page's fonts.
+    InlineSvg,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self::Base64
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(Self::Base64),
+            "percent" => Ok(Self::Percent),
+            "auto" => Ok(Self::Auto),
+            "inline-svg" => Ok(Self::InlineSvg),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A type that can be the value of an option.
+trait OptionValue: Sized {
+    fn parse_option(key: &str, value: Option<&str>) -> Result<Self, String>;
+}
+
+macro_rules! impl_option_value_from_str {
+    ($($ty:ty),*) => {$(
+        impl OptionValue for $ty {
+            fn parse_option(key: &str, value: Option<&str>) -> Result<Self, String> {
+                let value = value.ok_or_else(|| format!("option `{}` requires a value", key))?;
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid value for option `{}`: `{}`", key, value))
+            }
+        }
+    )*};
+}
+
+impl_option_value_from_str!(
+    String,
+    usize,
+    f32,
+    Keyword,
+    FontName,
+    Unit,
+    Encoding,
+    FenceChar,
+    AspectRatio,
+    Compat,
+    TextAnchor,
+    WidthMetric,
+    Theme,
+    ThemePresets,
+    Palette,
+    Fit,
+    Percentage,
+    Gradient
+);
+
+impl OptionValue for bool {
+    fn parse_option(key: &str, value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("true") => Ok(true),
+            Some("false") => Ok(false),
+            Some(value) => Err(format!(
+                "invalid value for option `{}`: `{}` (expected `true` or `false`)",
+                key, value
+            )),
+        }
+    }
+}
+
+impl<T: OptionValue> OptionValue for Option<T> {
+    fn parse_option(key: &str, value: Option<&str>) -> Result<Self, String> {
+        T::parse_option(key, value).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn option_names() {
+        for part in split_list(r#"alt="a, b",scale=10,border"#) {
+            let (key, _) = split_part(part);
+            assert!(OPTION_NAMES.contains(&key), "{}", key);
+        }
+
+        // Every listed option is recognized by the parser
+        for name in OPTION_NAMES {
+            if let Err(message) = DiagramOptions::default().set(name, None) {
+                assert!(!message.starts_with("unknown option"), "{}", message);
+            }
+        }
+        assert!(DiagramOptions::default()
+            .set("stroke-width", None)
+            .unwrap_err()
+            .starts_with("unknown option"));
+    }
+
+    #[test]
+    fn decimal_comma() {
+        let message = DiagramOptions::default().set_list("scale=1,5").unwrap_err();
+        assert!(
+            message.starts_with("unexpected number `5` after `,`; decimal numbers must use `.`"),
+            "{}",
+            message
+        );
+        // Quoted values can contain commas
+        assert!(DiagramOptions::default().set_list(r#"alt="1,5""#).is_ok());
+    }
+
+    #[test]
+    fn gradient() {
+        let mut options = DiagramOptions::default();
+        options.set_list("fill-gradient=#aaa,#333,border").unwrap();
+        let gradient = options.fill_gradient.unwrap();
+        assert_eq!((&*gradient.from, &*gradient.to), ("#aaa", "#333"));
+        assert!(options.border);
+
+        // Color names after the comma need quotes
+        let mut options = DiagramOptions::default();
+        options.set_list(r#"fill-gradient="white,black""#).unwrap();
+        let gradient = options.fill_gradient.unwrap();
+        assert_eq!((&*gradient.from, &*gradient.to), ("white", "black"));
+        let message = DiagramOptions::default()
+            .set_list("fill-gradient=white,black")
+            .unwrap_err();
+        assert!(
+            message.starts_with("invalid value for option `fill-gradient`: `white`"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn theme_presets() {
+        let presets: ThemePresets = "a:#111,#222,#333  b.c:red,green,blue".parse().unwrap();
+        let names: Vec<&str> = presets.0.iter().map(|(name, _)| &*name.0).collect();
+        assert_eq!(names, ["a", "b.c"]);
+        let colors = Theme::Custom(Keyword("b.c".to_owned()))
+            .colors(Some(&presets))
+            .unwrap();
+        assert_eq!(
+            (&*colors.stroke, &*colors.text, &*colors.background),
+            ("red", "green", "blue")
+        );
+        assert!(Theme::Custom(Keyword("d".to_owned()))
+            .colors(Some(&presets))
+            .is_none());
+
+        for invalid in &[
+            "a",
+            "a:#111,#222",
+            "a:#111,#222,#333,#444",
+            "a:red;,b,c",
+            "monokai:a,b,c",
+        ] {
+            assert!(invalid.parse::<ThemePresets>().is_err(), "{}", invalid);
+        }
+
+        // Hexadecimal colors don't need quotes in a list of options, but
+        // color names do
+        let mut options = DiagramOptions::default();
+        options
+            .set_list("theme-presets=dusk:#8be9fd,#f8f8f2,#282a36,theme=dusk")
+            .unwrap();
+        assert_eq!(options.theme_presets.unwrap().0.len(), 1);
+        let mut options = DiagramOptions::default();
+        options
+            .set_list(r#"theme-presets="dusk:red,green,blue""#)
+            .unwrap();
+        assert_eq!(options.theme_presets.unwrap().0.len(), 1);
+        let message = DiagramOptions::default()
+            .set_list("theme-presets=dusk:red,green,blue")
+            .unwrap_err();
+        assert!(
+            message.starts_with("invalid value for option `theme-presets`: `dusk:red`"),
+            "{}",
+            message
+        );
+    }
+}
@@ -1,15 +1,99 @@
 use proc_macro2::Span;
-use syn::{Error, Result};
+use syn::Error;
+
+use crate::{
+    error::SvgbobdocError,
+    options::{self, Compat, DiagramOptions, Encoding, Fit, Theme, Unit, WidthMetric},
+};
+
+/// Write a debug record to the standard error if the `trace` feature is
+/// enabled.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "trace") {
+            trace::record(format_args!($($arg)*));
+        }
+    };
+}
+
+/// The destination of the records written by `trace!`.
+mod trace {
+    use std::cell::Cell;
+    #[cfg(test)]
+    use std::cell::RefCell;
+    use std::fmt;
+
+    thread_local! {
+        /// Whether the records are suppressed, which they are while
+        /// `measure_groups` processes the fragments ahead of `step`.
+        static SUPPRESSED: Cell<bool> = Cell::new(false);
+        /// The records written by the tests, which are kept for inspection.
+        #[cfg(test)]
+        static RECORDS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    }
+
+    pub fn record(args: fmt::Arguments<'_>) {
+        if SUPPRESSED.with(Cell::get) {
+            return;
+        }
+        #[cfg(test)]
+        RECORDS.with(|records| records.borrow_mut().push(args.to_string()));
+        #[cfg(not(test))]
+        eprintln!("svgbobdoc: {}", args);
+    }
+
+    /// Call `f` with the records suppressed.
+    pub fn suppress<T>(f: impl FnOnce() -> T) -> T {
+        let suppressed = SUPPRESSED.with(|cell| cell.replace(true));
+        let result = f();
+        SUPPRESSED.with(|cell| cell.set(suppressed));
+        result
+    }
+
+    /// Remove and return the records written so far on this thread.
+    #[cfg(all(test, feature = "trace"))]
+    pub fn take_records() -> Vec<String> {
+        RECORDS.with(|records| records.take())
+    }
+}
 
 /// The current state of the code block finder.
 #[derive(Debug)]
 pub struct TextProcState {
     code_block: Option<CodeBlock>,
+    /// The options applied to every diagram unless overridden by a code fence.
+    defaults: DiagramOptions,
+    /// Non-fatal diagnostics collected so far.
+    warnings: Vec<Error>,
+    /// The number of diagrams converted so far.
+    diagram_count: usize,
+    /// The number of diagrams converted with `figure` so far.
+    figure_count: usize,
+    /// The link reference definitions of the diagrams emitted with
+    /// `reference-links`.
+    references: String,
+    /// The diagrams defined with `id`.
+    symbols: Vec<Symbol>,
+    /// The widths of the widest diagram in each `group` in pixels, found by
+    /// `measure_groups`.
+    group_widths: Vec<(String, f32)>,
+    /// Set when this is used by `measure_groups`, in which case diagrams are
+    /// measured instead of converted.
+    measuring: bool,
+    /// The times taken to convert the diagrams with `timings` (index, span of
+    /// the opening fence, and time).
+    timings: Vec<(usize, Span, std::time::Duration)>,
+    /// The options given by a directive comment on the previous line, which
+    /// apply to the diagram starting on the current line.
+    directive: Option<(String, Span)>,
 }
 
 #[derive(Debug)]
 struct CodeBlock {
     fence: String,
+    /// The block quote markers (`> `) and the asterisk of a block doc comment
+    /// (` * `) preceding the opening fence.
+    quote: String,
     captured: Option<CapturedCodeBlock>,
     start: Span,
 }
@@ -18,32 +102,104 @@ struct CodeBlock {
 struct CapturedCodeBlock {
     content: String,
     params: CodeBlockParams,
+    /// Non-fatal problems found while capturing the content.
+    warnings: Vec<String>,
 }
 
 #[derive(Debug)]
 struct CodeBlockParams {
     label: Option<String>,
+    options: DiagramOptions,
+    /// The characters replaced before rendering (`subst:X=Y`).
+    substitutions: Vec<(char, char)>,
+    /// The width of the widest diagram in the same `group` in pixels.
+    min_width: Option<f32>,
 }
 
 /// The output of `TextProcState::step`.
+///
+/// The outputs of consecutive fragments are joined by line breaks, like the
+/// fragments themselves.
 #[derive(Debug)]
 pub enum TextProcOutput {
     /// Output the input fragment (`#[doc = "..."]`) without modification,
     /// preserving its positional information.
     Passthrough,
-    /// Output nothing.
+    /// Output nothing. The line break joining this to the next output is still
+    /// inserted.
     Empty,
     /// Output a new documentation text. The positional association between the
     /// input fragment and `.0` is erased.
+    ///
+    /// Removed lines, including the last one, leave no line breaks. `.0` ends
+    /// with a line break only if the input fragment does, or if its last line
+    /// is replaced with inline SVG code or a link reference definition, which
+    /// end with one.
     Fragment(String),
 }
 
+impl TextProcOutput {
+    /// Get the text to output for the input fragment `original`, or `None` if
+    /// nothing is output.
+    pub fn into_string(self, original: &str) -> Option<String> {
+        match self {
+            Self::Passthrough => Some(original.to_owned()),
+            Self::Empty => None,
+            Self::Fragment(fragment) => Some(fragment),
+        }
+    }
+}
+
 impl TextProcState {
-    pub fn new() -> Self {
-        Self { code_block: None }
+    pub fn new(defaults: DiagramOptions) -> Self {
+        Self {
+            code_block: None,
+            defaults,
+            warnings: Vec::new(),
+            diagram_count: 0,
+            figure_count: 0,
+            references: String::new(),
+            symbols: Vec::new(),
+            group_widths: Vec::new(),
+            measuring: false,
+            timings: Vec::new(),
+            directive: None,
+        }
+    }
+
+    /// Find the width of each group of diagrams (`group`) in the fragments to
+    /// be processed by `step` so that the diagrams in a group are made as wide
+    /// as the widest one. This is a separate pass over the fragments because a
+    /// diagram can be followed by a wider one in the same group.
+    pub fn measure_groups<'a>(
+        &mut self,
+        fragments: impl IntoIterator<Item = (&'a str, Span)>,
+    ) -> Result<(), SvgbobdocError> {
+        let mut measuring = Self::new(self.defaults.clone());
+        measuring.measuring = true;
+        trace::suppress(|| {
+            for (fragment, span) in fragments {
+                measuring.step(fragment, span)?;
+            }
+            Ok(())
+        })?;
+        self.group_widths = measuring.group_widths;
+        Ok(())
     }
 
-    pub fn step(&mut self, fragment: &str, span: Span) -> TextProcOutput {
+    /// Process a fragment (the value of a `#[doc = "..."]` attribute). The
+    /// fragments are joined by line breaks, so a code block opened in a
+    /// fragment can be closed in a later one. A code block left open by the
+    /// last fragment is reported by `finalize`.
+    pub fn step(&mut self, fragment: &str, span: Span) -> Result<TextProcOutput, SvgbobdocError> {
+        if self.defaults.normalize_line_endings && fragment.contains('\r') {
+            let normalized = fragment.replace("\r\n", "\n").replace('\r', "\n");
+            return Ok(match self.step(&normalized, span)? {
+                TextProcOutput::Passthrough => TextProcOutput::Fragment(normalized),
+                output => output,
+            });
+        }
+
         let mut i = 0;
 
         let mut new_frag: Option<String> = None;
@@ -59,11 +215,15 @@ impl TextProcState {
 
         // Disables "pass-through" mode, preparing `new_frag` for custom
         // generation.
+        //
+        // `i` is always at the start of a line, i.e., after a `\n`, which is
+        // a character boundary even if the preceding text includes multi-byte
+        // characters.
         macro_rules! prepare_nonpassthrough_emission {
             () => {
                 if new_frag.is_none() {
                     new_frag = Some(if passthrough {
-                        fragment[0..i].to_owned()
+                        fragment_prefix(fragment, i).to_owned()
                     } else {
                         String::new()
                     });
@@ -73,14 +233,16 @@ impl TextProcState {
         }
 
         /// ```text
-        /// ^( *(?:`{3,}|~{3,}))\s*(.*?)\s*$
+        /// ^( *(?:`{3,}|~{3,}|X{3,}))\s*(.*?)\s*$
         /// ```
-        fn detect_fence(s: &str) -> Option<(&str, &str)> {
+        ///
+        /// where `X` is `extra_ch`.
+        fn detect_fence(s: &str, extra_ch: Option<u8>) -> Option<(&str, &str)> {
             let bytes = s.as_bytes();
             let fence_len = {
                 let indent = bytes.iter().take_while(|&&b| b == b' ').count();
                 let fence_ch = *bytes.get(indent)?;
-                if !matches!(fence_ch, b'`' | b'~') {
+                if !matches!(fence_ch, b'`' | b'~') && Some(fence_ch) != extra_ch {
                     return None;
                 }
                 let fence = bytes[indent..]
@@ -98,6 +260,71 @@ impl TextProcState {
             Some((fence, rest.trim()))
         }
 
+        /// Split `s` into the block quote markers (`> > `) and the rest.
+        fn split_blockquote(s: &str) -> (&str, &str) {
+            let bytes = s.as_bytes();
+            let mut i = 0;
+            loop {
+                let indent = bytes[i..].iter().take_while(|&&b| b == b' ').count();
+                if indent > 3 || bytes.get(i + indent) != Some(&b'>') {
+                    break;
+                }
+                i += indent + 1;
+                if bytes.get(i) == Some(&b' ') {
+                    i += 1;
+                }
+            }
+            s.split_at(i)
+        }
+
+        /// Split `s` into the leading asterisk of a block doc comment line
+        /// (` * `) and the rest.
+        fn split_asterisk(s: &str) -> (&str, &str) {
+            let indent = s.len() - s.trim_start_matches(|ch| ch == ' ' || ch == '\t').len();
+            if s[indent..].starts_with('*') {
+                let end = indent + 1;
+                let end = if s[end..].starts_with(' ') {
+                    end + 1
+                } else {
+                    end
+                };
+                s.split_at(end)
+            } else {
+                ("", s)
+            }
+        }
+
+        /// Check if `s` is the content of a block doc comment (`/** ... */`)
+        /// with an asterisk on every line after the first one.
+        fn is_asterisk_prefixed(s: &str) -> bool {
+            let mut lines = s.lines().skip(1).filter(|line| !line.trim().is_empty());
+            let mut any = false;
+            lines.all(|line| {
+                any = true;
+                !split_asterisk(line).0.is_empty()
+            }) && any
+        }
+
+        /// Check if `ch` is a character that doesn't render but prevents a
+        /// code fence from being recognized, such as a byte order mark or a
+        /// zero-width space.
+        fn is_invisible(ch: char) -> bool {
+            matches!(
+                ch,
+                '\u{feff}' | '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}'
+            )
+        }
+
+        /// Extract the options from a directive comment
+        /// (`<!-- svgbob: key=value -->`).
+        fn parse_directive(s: &str) -> Option<&str> {
+            let s = s.trim().strip_prefix("<!--")?.strip_suffix("-->")?;
+            s.trim().strip_prefix("svgbob:").map(str::trim)
+        }
+
+        /// Remove the prefix of `line` matching the spaces and tabs of
+        /// `indent`. Only single-byte characters are removed, so the result
+        /// is sliced at a character boundary.
         fn remove_indent<'a>(mut line: &'a str, mut indent: &str) -> &'a str {
             while line.len() > 0
                 && indent.len() > 0
@@ -110,6 +337,14 @@ impl TextProcState {
             line
         }
 
+        // rustdoc removes the asterisks, so they are not part of code blocks
+        let asterisk_prefixed = is_asterisk_prefixed(fragment);
+
+        let extra_fence_ch = self.defaults.fence_char.map(|ch| ch.0);
+
+        // Set if the last line is removed without replacement
+        let last_line_removed;
+
         loop {
             let next_break = fragment[i..].find('\n');
 
@@ -122,65 +357,228 @@ impl TextProcState {
 
             let mut close_code_block = false;
             let mut passthrough_line = true;
+            // The line prefix (block quote markers and asterisks) to output in
+            // place of a removed line
+            let mut line_quote = String::new();
+
+            let (asterisk, unprefixed) = if asterisk_prefixed {
+                split_asterisk(line)
+            } else {
+                ("", line)
+            };
+            // e.g., a byte order mark at the beginning of an included file
+            let unprefixed = unprefixed.trim_start_matches(is_invisible);
+
+            // A directive only applies to the line immediately following it
+            let mut directive = self.directive.take();
 
             if let Some(code_block) = &mut self.code_block {
+                // Code blocks in block quotes have the markers on every line
+                let line = if code_block.quote.contains('>') {
+                    split_blockquote(unprefixed).1
+                } else {
+                    unprefixed
+                };
+
                 if line == code_block.fence {
                     // Reached the end of the code block
                     if let Some(mut captured) = code_block.captured.take() {
                         passthrough_line = false;
                         prepare_nonpassthrough_emission!();
 
-                        // Convert this captured code block to a SVG diagram.
-                        captured.content.pop(); // Remove trailing "\n"
-                        convert_diagram(
-                            &captured.content,
-                            new_frag.as_mut().unwrap(),
-                            captured.params,
-                        );
+                        if !captured.params.options.strip {
+                            // Convert this captured code block to a SVG diagram.
+                            captured.apply_front_matter().map_err(|message| {
+                                SvgbobdocError::InvalidOptions {
+                                    span: code_block.start,
+                                    message,
+                                }
+                            })?;
+                            if !captured.params.options.keep_trailing_newline {
+                                captured.content.pop(); // Remove trailing "\n"
+                            }
+                            trace!(
+                                "closed a svgbob code block with {} line(s)",
+                                captured.content.lines().count()
+                            );
+                            if let Some(group) = &captured.params.options.group {
+                                let entry = self
+                                    .group_widths
+                                    .iter_mut()
+                                    .find(|(name, _)| *name == group.0);
+                                if self.measuring {
+                                    let width = measure_width(&captured.content, &captured.params);
+                                    match entry {
+                                        Some((_, max_width)) => *max_width = width.max(*max_width),
+                                        None => self.group_widths.push((group.0.clone(), width)),
+                                    }
+                                } else {
+                                    captured.params.min_width = entry.map(|&mut (_, width)| width);
+                                }
+                            }
+
+                            // Only the sizes are needed in the measurement pass
+                            if !self.measuring {
+                                let new_frag = new_frag.as_mut().unwrap();
+                                *new_frag += &code_block.quote;
+                                let strict = captured.params.options.strict_render;
+                                let literal_safe = captured.params.options.literal_safe;
+                                let mut warnings = std::mem::take(&mut captured.warnings);
+                                self.diagram_count += 1;
+                                let figure = captured.params.options.figure;
+                                let timed = captured.params.options.timings;
+                                let start = new_frag.len();
+                                let started = std::time::Instant::now();
+                                let result = convert_diagram(
+                                    &captured.content,
+                                    new_frag,
+                                    captured.params,
+                                    self.diagram_count,
+                                    &mut self.references,
+                                    &mut self.symbols,
+                                    &mut warnings,
+                                );
+                                if timed {
+                                    self.timings.push((
+                                        self.diagram_count,
+                                        code_block.start,
+                                        started.elapsed(),
+                                    ));
+                                }
+
+                                if result.is_ok() && figure {
+                                    self.figure_count += 1;
+                                    wrap_with_figure(new_frag, start, self.figure_count);
+                                }
+
+                                if let Err(message) = result {
+                                    if strict {
+                                        return Err(SvgbobdocError::Render {
+                                            span: code_block.start,
+                                            message,
+                                        });
+                                    }
+
+                                    // Let the other diagrams render
+                                    new_frag.truncate(start);
+                                    write_error_placeholder(new_frag, &message, literal_safe);
+                                    warnings.push(message);
+                                }
+
+                                for message in warnings {
+                                    if strict {
+                                        return Err(SvgbobdocError::Render {
+                                            span: code_block.start,
+                                            message,
+                                        });
+                                    }
+                                    self.warnings.push(Error::new(code_block.start, message));
+                                }
+
+                                if next_break.is_some() {
+                                    // Keep the following text on its own line
+                                    new_frag.push('\n');
+                                }
+                            }
+                        }
                     }
 
                     close_code_block = true;
                 } else {
                     if let Some(captured) = &mut code_block.captured {
+                        let is_opening_fence = detect_fence(line, extra_fence_ch)
+                            .map_or(false, |(_, language)| is_svgbob_fence(language));
+                        if is_opening_fence {
+                            // The art of the next diagram is being captured
+                            captured.warnings.push(format!(
+                                "line {} of the svgbob diagram looks like the opening \
+                                 fence of another diagram; the closing fence may be \
+                                 missing",
+                                captured.content.lines().count() + 1
+                            ));
+                        }
+
                         captured.content += remove_indent(line, &code_block.fence);
                         captured.content.push('\n');
                         passthrough_line = false;
+                        line_quote = code_block.quote.trim_end().to_owned();
                     }
                 }
             } else {
                 // Detect a code block
-                if let Some((fence, language)) = detect_fence(line) {
+                let (quote, line) = if self.defaults.blockquote {
+                    split_blockquote(unprefixed)
+                } else {
+                    ("", unprefixed)
+                };
+                let quote = format!("{}{}", asterisk, quote);
+                if let Some((fence, language)) = detect_fence(line, extra_fence_ch) {
                     let mut code_block = CodeBlock {
                         fence: fence.to_owned(),
+                        quote: quote.clone(),
                         captured: None,
                         start: span,
                     };
 
-                    let params: Option<CodeBlockParams> = language
-                        .strip_prefix("svgbob")
-                        .and_then(|rest| {
-                            if rest.is_empty() {
-                                Some("") // exactly "svgbob"
-                            } else {
-                                rest.strip_prefix(",") // `Some` if "svgbob,[...]"
-                            }
-                        })
-                        .map(|params| params.parse().unwrap());
+                    let rest = language.strip_prefix("svgbob");
+
+                    if rest.map_or(false, |rest| rest.starts_with(char::is_whitespace)) {
+                        // e.g., "```svgbob +--+". The rest would be lost as
+                        // part of the info string.
+                        return Err(SvgbobdocError::InvalidFence {
+                            span,
+                            message: "unexpected text after `svgbob` in the code fence \
+                                      header; the diagram must start on the next line"
+                                .to_owned(),
+                        });
+                    }
+
+                    if let Some(params) = svgbob_params(language) {
+                        let directive_options;
+                        let defaults = if let Some((list, span)) = directive.take() {
+                            let mut options = self.defaults.clone();
+                            options.set_list(&list).map_err(|message| {
+                                SvgbobdocError::InvalidOptions { span, message }
+                            })?;
+                            directive_options = options;
+                            &directive_options
+                        } else {
+                            &self.defaults
+                        };
+                        let params = CodeBlockParams::parse(params, defaults)
+                            .map_err(|message| SvgbobdocError::InvalidOptions { span, message })?;
 
-                    if let Some(params) = params {
                         // This is the code blcok we are interested in.
                         // Capture the contents.
+                        trace!("opened a svgbob code block: {:?}", line);
                         passthrough_line = false;
+                        line_quote = quote.trim_end().to_owned();
                         code_block.captured = Some(CapturedCodeBlock {
                             content: String::new(),
                             params,
+                            warnings: Vec::new(),
                         });
+                    } else if self.defaults.audit && is_misspelled_svgbob(language) {
+                        self.warnings.push(Error::new(
+                            span,
+                            format!(
+                                "the code block's language `{}` looks like a \
+                                 misspelling of `svgbob`",
+                                language.split(',').next().unwrap_or("")
+                            ),
+                        ));
                     }
 
                     self.code_block = Some(code_block);
+                } else if let Some(list) = parse_directive(line) {
+                    self.directive = Some((list.to_owned(), span));
                 }
             }
 
+            if let Some((_, span)) = directive {
+                self.warnings.push(unused_directive_warning(span));
+            }
+
             if close_code_block {
                 self.code_block = None;
             }
@@ -193,50 +591,360 @@ impl TextProcState {
                     }
                 }
             } else {
-                if passthrough {
+                if passthrough || !line_quote.is_empty() {
                     prepare_nonpassthrough_emission!();
                 }
+                if !line_quote.is_empty() {
+                    // Don't break the block quote with an empty line
+                    let new_frag = new_frag.as_mut().unwrap();
+                    *new_frag += &line_quote;
+                    if next_break.is_some() {
+                        new_frag.push('\n');
+                    }
+                }
             }
 
             if let Some(next_break) = next_break {
                 i += next_break + 1;
             } else {
+                last_line_removed = !passthrough_line && line_quote.is_empty() && !close_code_block;
                 break;
             }
         }
 
-        if let Some(new_frag) = new_frag {
+        Ok(if let Some(mut new_frag) = new_frag {
+            if last_line_removed && new_frag.ends_with('\n') {
+                // Remove the line break that preceded the last line
+                new_frag.pop();
+            }
             TextProcOutput::Fragment(new_frag)
         } else if passthrough {
             TextProcOutput::Passthrough
         } else {
             TextProcOutput::Empty
-        }
+        })
     }
 
-    pub fn finalize(self) -> Result<()> {
+    /// Check the final state and return the text to append to the output and
+    /// the warnings collected so far.
+    pub fn finalize(mut self) -> Result<TextProcEnd, SvgbobdocError> {
         if let Some(code_block) = self.code_block {
             if code_block.captured.is_some() {
-                return Err(Error::new(code_block.start, "unclosed code block"));
+                return Err(SvgbobdocError::UnclosedCodeBlock {
+                    span: code_block.start,
+                });
+            }
+        }
+        if let Some((_, span)) = self.directive {
+            self.warnings.push(unused_directive_warning(span));
+        }
+
+        if !self.timings.is_empty() {
+            // Reported at the slowest diagram
+            self.timings.sort_by(|a, b| b.2.cmp(&a.2));
+            let list: Vec<String> = self
+                .timings
+                .iter()
+                .take(MAX_REPORTED_TIMINGS)
+                .map(|(index, _, time)| {
+                    format!("diagram {}: {:.1}ms", index, time.as_secs_f64() * 1000.0)
+                })
+                .collect();
+            self.warnings.push(Error::new(
+                self.timings[0].1,
+                format!(
+                    "svgbob render times of {} diagram(s), slowest first: {}",
+                    self.timings.len(),
+                    list.join(", ")
+                ),
+            ));
+        }
+
+        let trailer = if self.references.is_empty() {
+            String::new()
+        } else {
+            // A blank line ends any paragraph that would otherwise absorb the
+            // definitions
+            format!("\n{}", self.references)
+        };
+
+        Ok(TextProcEnd {
+            trailer,
+            warnings: self.warnings,
+        })
+    }
+}
+
+/// The maximum number of diagrams listed in the summary of `timings`.
+const MAX_REPORTED_TIMINGS: usize = 5;
+
+/// Get the part of `fragment` before the byte index `i`, which must be at a
+/// character boundary.
+fn fragment_prefix(fragment: &str, i: usize) -> &str {
+    debug_assert!(
+        fragment.is_char_boundary(i),
+        "fragment sliced inside a character at byte {}",
+        i
+    );
+    &fragment[..i]
+}
+
+fn unused_directive_warning(span: Span) -> Error {
+    Error::new(
+        span,
+        "the svgbob directive comment isn't immediately followed by the opening \
+         fence of a svgbob diagram",
+    )
+}
+
+/// Process the fragments of a doc comment (the values of its `#[doc = ...]`
+/// attributes and their spans) and return the resulting documentation text and
+/// the warnings.
+pub fn transform_fragments(
+    defaults: DiagramOptions,
+    fragments: Vec<(String, Span)>,
+) -> Result<(String, Vec<Error>), SvgbobdocError> {
+    let (mut fragments, spans): (Vec<String>, Vec<Span>) = fragments.into_iter().unzip();
+    if defaults.dedent {
+        // The common indentation can only be found after seeing all fragments
+        remove_common_indent(&mut fragments);
+    }
+
+    let grouped = may_have_groups(&defaults, &fragments);
+    let mut text_proc = TextProcState::new(defaults);
+    if grouped {
+        // The widths of groups of diagrams can only be found after seeing all
+        // fragments
+        text_proc.measure_groups(
+            fragments
+                .iter()
+                .map(String::as_str)
+                .zip(spans.iter().copied()),
+        )?;
+    }
+
+    let mut output = String::new();
+    for (fragment, &span) in fragments.iter().zip(&spans) {
+        if let Some(fr) = text_proc.step(fragment, span)?.into_string(fragment) {
+            output.push_str(&fr);
+        }
+        output.push_str("\n");
+    }
+    let end = text_proc.finalize()?;
+    output.push_str(&end.trailer);
+    Ok((output, end.warnings))
+}
+
+/// Check if any diagram in `fragments` may be in a `group`. Measuring the
+/// groups renders every diagram once more, so it's skipped otherwise.
+fn may_have_groups(defaults: &DiagramOptions, fragments: &[String]) -> bool {
+    defaults.group.is_some() || fragments.iter().any(|fragment| fragment.contains("group"))
+}
+
+/// Remove the leading whitespace common to all non-blank lines of `fragments`.
+pub fn remove_common_indent(fragments: &mut [String]) {
+    let mut common: Option<&str> = None;
+    for line in fragments.iter().flat_map(|fragment| fragment.lines()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let indent_len = line.len() - line.trim_start_matches(|ch| ch == ' ' || ch == '\t').len();
+        let indent = &line[..indent_len];
+        common = Some(match common {
+            None => indent,
+            Some(common) => {
+                let len = common
+                    .bytes()
+                    .zip(indent.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                &common[..len]
+            }
+        });
+    }
+
+    let common = match common {
+        Some(common) if !common.is_empty() => common.to_owned(),
+        _ => return,
+    };
+    for fragment in fragments.iter_mut() {
+        let lines: Vec<&str> = fragment
+            .split('\n')
+            .map(|line| match line.strip_prefix(&*common) {
+                Some(line) => line,
+                // A blank line can be shorter than the indentation
+                None if line.trim().is_empty() => "",
+                None => line,
+            })
+            .collect();
+        *fragment = lines.join("\n");
+    }
+}
+
+/// The output of `TextProcState::finalize`.
+#[derive(Debug)]
+pub struct TextProcEnd {
+    /// The text to append to the output.
+    pub trailer: String,
+    /// Non-fatal diagnostics.
+    pub warnings: Vec<Error>,
+}
+
+/// A diagram defined as a `<symbol>` element by the `id` option.
+#[derive(Debug)]
+struct Symbol {
+    id: String,
+    /// The start tag of the root element, which is reused by references.
+    start_tag: String,
+}
+
+impl CapturedCodeBlock {
+    /// Remove the front matter from the content and apply the options in it.
+    /// The front matter is a list of `key: value` lines between `---` lines at
+    /// the beginning of the content:
+    ///
+    /// ```text
+    /// ---
+    /// scale: 10
+    /// alt: "A diagram"
+    /// ---
+    /// ```
+    ///
+    /// A key without a value (`border:`) sets a flag. `---` is also a valid
+    /// line of a diagram, so the content is left as is unless the front matter
+    /// has at least one line and every line sets a known option.
+    fn apply_front_matter(&mut self) -> std::result::Result<(), String> {
+        fn parse_entry(line: &str) -> Option<(String, &str)> {
+            let i = line.find(':')?;
+            // `font_weight` is accepted like in `transform!`
+            let key = line[..i].trim().replace('_', "-");
+            if options::OPTION_NAMES.contains(&&*key) {
+                Some((key, line[i + 1..].trim()))
+            } else {
+                None
+            }
+        }
+
+        let rest = match self.content.strip_prefix("---\n") {
+            Some(rest) => rest,
+            None => return Ok(()),
+        };
+
+        let mut entries = Vec::new();
+        let mut len = "---\n".len();
+        let mut closed = false;
+        for line in rest.split_inclusive('\n') {
+            len += line.len();
+            let line = line.trim_end();
+            if line == "---" {
+                closed = true;
+                break;
+            }
+            match parse_entry(line) {
+                Some(entry) => entries.push(entry),
+                None => return Ok(()),
             }
         }
+        if !closed || entries.is_empty() {
+            return Ok(());
+        }
+
+        for (key, value) in entries {
+            if value.is_empty() {
+                self.params.options.set_part(&key)?;
+            } else {
+                self.params
+                    .options
+                    .set_part(&format!("{}={}", key, value))?;
+            }
+        }
+        self.content.drain(..len);
+
         Ok(())
     }
 }
 
-impl std::str::FromStr for CodeBlockParams {
-    type Err = std::convert::Infallible;
+/// Check if a code block with the info string `info` is a diagram.
+pub fn is_svgbob_fence(info: &str) -> bool {
+    svgbob_params(info).is_some()
+}
+
+/// Get the part of the info string `info` following `svgbob,`, or `None` if
+/// the code block isn't a diagram.
+fn svgbob_params(info: &str) -> Option<&str> {
+    let rest = info.strip_prefix("svgbob")?;
+    if rest.is_empty() {
+        Some("") // exactly "svgbob"
+    } else {
+        rest.strip_prefix(",") // `Some` if "svgbob,[...]"
+    }
+}
+
+/// Check if the language of a code block with the info string `info` isn't
+/// `svgbob` but is within two edits of it (e.g., `svbgob`, `SVGBOB`).
+fn is_misspelled_svgbob(info: &str) -> bool {
+    const NAME: &[u8] = b"svgbob";
+    let language = info.split(',').next().unwrap_or("").as_bytes();
+    if language == NAME || language.len() > NAME.len() + 2 {
+        return false;
+    }
+
+    // The Levenshtein distance, ignoring case, row by row
+    let mut row: Vec<usize> = (0..=NAME.len()).collect();
+    for (i, &a) in language.iter().enumerate() {
+        let mut next = vec![i + 1];
+        for (k, &b) in NAME.iter().enumerate() {
+            let cost = if a.to_ascii_lowercase() == b { 0 } else { 1 };
+            next.push((row[k] + cost).min(row[k + 1] + 1).min(next[k] + 1));
+        }
+        row = next;
+    }
+    row[NAME.len()] <= 2
+}
 
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let mut this = CodeBlockParams { label: None };
+impl CodeBlockParams {
+    /// Parse the part of a code fence header following `svgbob,`. The options
+    /// not specified there are taken from `defaults`.
+    fn parse(s: &str, defaults: &DiagramOptions) -> std::result::Result<Self, String> {
+        let mut this = CodeBlockParams {
+            label: None,
+            options: defaults.clone(),
+            substitutions: Vec::new(),
+            min_width: None,
+        };
 
-        let parts = s.split(",").map(|s| s.trim());
-        for part in parts {
+        for part in options::split_list(s) {
             if let Some(label) = part
                 .strip_prefix("[")
                 .and_then(|part| part.strip_suffix("]"))
             {
                 this.label = Some(label.to_owned());
+            } else if part.starts_with("lang:") {
+                // A language hint for other tools, e.g., `lang:rust`
+            } else if let Some(subst) = part.strip_prefix("subst:") {
+                let mut chars = subst.chars();
+                match (chars.next(), chars.next(), chars.next(), chars.next()) {
+                    (Some(from), Some('='), Some(to), None) => {
+                        this.substitutions.push((from, to));
+                    }
+                    _ => {
+                        return Err(format!(
+                            "invalid substitution `{}` (expected `subst:X=Y`)",
+                            part
+                        ))
+                    }
+                }
+            } else {
+                let (key, value) = options::split_part(part);
+                if options::OPTION_NAMES.contains(&key)
+                    || (value.is_none() && key.bytes().all(|b| b.is_ascii_digit()))
+                {
+                    // `set_part` explains a stray number (`scale=1,5`)
+                    this.options.set_part(part)?;
+                } else {
+                    // e.g., an attribute for another tool
+                    trace!("ignoring an unknown option: {:?}", part);
+                }
             }
         }
 
@@ -252,239 +960,4096 @@ impl std::str::FromStr for CodeBlockParams {
 const DIAGRAM_FONT: &str =
     "'Source Code Pro','Andale Mono','Segoe UI Mono','Dejavu Sans Mono','Consolas',monospace";
 
-fn convert_diagram(art: &str, output: &mut String, params: CodeBlockParams) {
-    let svg_code = to_svg(art);
-
-    // Output the SVG as an image element
-    use std::fmt::Write;
-    let svg_base64 = base64::encode(&*svg_code);
-
-    if let Some(label) = params.label {
-        writeln!(
-            output,
-            "\n[{}]: data:image/svg+xml;base64,{}",
-            label, svg_base64
-        )
-        .unwrap();
-    } else {
-        write!(output, "![](data:image/svg+xml;base64,{})", svg_base64).unwrap();
+/// The `font-family` of a diagram, which is `DIAGRAM_FONT` preceded by
+/// `font_primary` if given.
+fn font_family(options: &DiagramOptions) -> String {
+    match &options.font_primary {
+        Some(font) => format!("'{}',{}", font.0, DIAGRAM_FONT),
+        None => DIAGRAM_FONT.to_owned(),
     }
 }
 
-#[cfg(feature = "enable")]
-fn to_svg(art: &str) -> String {
-    use svgbob::{
-        sauron::{html::attributes::AttributeValue, Attribute},
-        Node,
-    };
-
-    // Convert the diagram to SVG
-    let mut settings = svgbob::Settings::default();
-    settings.stroke_width = 1.0;
-    settings.font_family = DIAGRAM_FONT.to_owned();
-    settings.font_size = 13;
+/// The font size used for diagrams, measured in pixels.
+const DIAGRAM_FONT_SIZE: usize = 13;
 
-    let cb = svgbob::CellBuffer::from(art);
-    let (mut node, _, _): (svgbob::Node<()>, _, _) = cb.get_node_with_size(&settings);
+/// The default value of `DiagramOptions::warn_width`, measured in pixels.
+/// This is the maximum width of rustdoc's content column.
+const DEFAULT_WARN_WIDTH: usize = 960;
 
-    traverse_pre_order_mut(&mut node, &mut |node| {
-        match node {
-            Node::Element(elem) if elem.tag == "text" => {
-                // Fix the horizontal layouting of texts by adding a `textLength` attribute
-                // to `<text>` elements.
-                let mut width = 0;
-                for child in elem.get_children() {
-                    if let Some(text) = child.text() {
-                        width += xml_text_width(text);
-                    }
-                }
+/// The default value of `DiagramOptions::dpi`. This is the resolution of
+/// CSS pixels.
+const DEFAULT_DPI: f32 = 96.0;
 
-                let text_len = width as f32 * settings.scale as f32;
-                elem.attrs.push(Attribute::new(
-                    None,
-                    "textLength",
-                    AttributeValue::from_value(text_len.into()),
-                ));
+/// The default value of `DiagramOptions::scale`. This matches svgbob's
+/// default.
+const DEFAULT_SCALE: f32 = 8.0;
 
-                return false;
-            }
-            _ => {}
-        }
+/// The image defined for a link label by `dry-run`, an empty SVG image, so
+/// that the references to the label still resolve.
+const DRY_RUN_PLACEHOLDER: &str =
+    "data:image/svg+xml,%3Csvg%20xmlns=%22http://www.w3.org/2000/svg%22/%3E";
 
-        true
-    });
+/// Apply `src`, the substitutions (`subst:X=Y`), and `trim-blank-lines` to
+/// the captured content of a diagram.
+fn prepare_art<'a>(
+    art: &'a str,
+    params: &CodeBlockParams,
+    warnings: &mut Vec<String>,
+) -> std::result::Result<std::borrow::Cow<'a, str>, String> {
+    use std::borrow::Cow;
 
-    // FIXME: Replace with let-else when stabilized
-    let elem = if let svgbob::Node::Element(elem) = &mut node {
-        elem
+    let mut art = if let Some(src) = &params.options.src {
+        if !art.trim().is_empty() {
+            warnings.push("the content of a diagram with `src` is ignored".to_owned());
+        }
+        let mut source = read_source(src)?;
+        if source.ends_with('\n') {
+            source.pop();
+        }
+        Cow::Owned(source)
     } else {
-        unreachable!()
+        Cow::Borrowed(art)
     };
 
-    // Patch the root element (`<svg>`)
-    for attr in elem.attrs.iter_mut() {
-        match *attr.name() {
-            "height" => {
-                // Fix the height of the image
-                // <https://github.com/ivanceras/svgbob/issues/77>
-                let new_height = settings.scale * 2.0 * art.lines().count() as f32;
-                *attr = Attribute::new(
-                    None,
-                    "height",
-                    AttributeValue::from_value(new_height.into()),
-                );
-            }
-            _ => {}
-        }
+    if !params.substitutions.is_empty() {
+        art = Cow::Owned(
+            art.chars()
+                .map(
+                    |ch| match params.substitutions.iter().find(|&&(from, _)| from == ch) {
+                        Some(&(_, to)) => to,
+                        None => ch,
+                    },
+                )
+                .collect(),
+        );
     }
-    elem.attrs.push(Attribute::new(
-        None,
-        "style",
-        AttributeValue::from_value("transform:translate(0.5px,0.5px)".into()),
-    ));
 
-    use svgbob::Render;
-    let mut svg_code = String::new();
-    node.render(&mut svg_code).unwrap();
+    if params.options.trim_blank_lines {
+        // Keep the last non-blank line intact
+        let end = match art.rfind(|ch: char| !ch.is_whitespace()) {
+            Some(i) => art[i..].find('\n').map_or(art.len(), |k| i + k),
+            None => 0,
+        };
+        art = match art {
+            Cow::Borrowed(art) => Cow::Borrowed(&art[..end]),
+            Cow::Owned(mut art) => {
+                art.truncate(end);
+                Cow::Owned(art)
+            }
+        };
+    }
 
-    svg_code
+    Ok(art)
 }
 
-/// Like [`unicode_width::UnicodeWidthStr`] but handles some entity references
-/// (e.g., `&amp;`). Assumes the input is in a valid form of an XML text node.
-#[cfg(feature = "enable")]
-fn xml_text_width(html_text: &str) -> usize {
-    use unicode_width::UnicodeWidthStr;
-    html_text
-        .split('&')
-        .enumerate()
-        .map(|(i, mut part)| {
-            if i > 0 {
-                if let Some(k) = part.find(';') {
-                    // "& a m p ;"
-                    //  ^ ^^^^^ ^
-                    //  │   │   └─ This part is preserved so that this entity is
-                    //  │   │      counted as one cell
-                    //  │   └─ We remove this part now
-                    //  └─ This part is removed by `split`
-                    part = &part[k..];
-                }
-            }
-            part.width()
-        })
-        .sum()
-}
+/// Convert a diagram and append the Markdown text to `output`. `index` is the
+/// 1-based index of the diagram in the doc comment. Link reference definitions
+/// to be placed at the end of the doc comment are appended to `references`.
+/// `symbols` holds the diagrams that can be referenced by `ref`. Non-fatal
+/// problems are reported through `warnings`.
+fn convert_diagram(
+    art: &str,
+    output: &mut String,
+    params: CodeBlockParams,
+    index: usize,
+    references: &mut String,
+    symbols: &mut Vec<Symbol>,
+    warnings: &mut Vec<String>,
+) -> std::result::Result<(), String> {
+    let art = prepare_art(art, &params, warnings)?;
+    let art = &*art;
 
-#[cfg(feature = "enable")]
-fn traverse_pre_order_mut<MSG>(
-    node: &mut svgbob::Node<MSG>,
-    cb: &mut dyn FnMut(&mut svgbob::Node<MSG>) -> bool,
-) {
-    if cb(node) {
-        if let Some(children) = node.children_mut() {
-            for child in children.iter_mut() {
-                traverse_pre_order_mut(child, cb);
-            }
+    if params.options.ref_id.is_some() {
+        if !art.trim().is_empty() {
+            warnings.push("the content of a diagram with `ref` is ignored".to_owned());
+        }
+    } else if art.trim().is_empty() {
+        warnings.push("svgbob diagram is empty".to_owned());
+    }
+    if art.contains('\t') {
+        // svgbob treats a tab as a single cell, which breaks the alignment of
+        // everything after it
+        warnings.push(
+            "svgbob diagram contains tab characters, which are rendered as a \
+             single cell; use spaces instead"
+                .to_owned(),
+        );
+    }
+
+    let overflowing_lines = find_overflowing_labels(art);
+    if !overflowing_lines.is_empty() {
+        let lines: Vec<String> = overflowing_lines.iter().map(|i| i.to_string()).collect();
+        warnings.push(format!(
+            "texts on line(s) {} of the svgbob diagram overflow their enclosing boxes",
+            lines.join(", ")
+        ));
+    }
+
+    let options = &params.options;
+    trace!(
+        "converting diagram {} (label: {:?}) with {:?}",
+        index,
+        params.label,
+        options
+    );
+
+    if let Some(theme) = &options.theme {
+        if let (Theme::Custom(name), None) = (theme, theme.colors(options.theme_presets.as_ref())) {
+            return Err(format!(
+                "unknown theme `{}`; built-in themes are `monokai` and `solarized`, \
+                 and others can be defined by `theme-presets`",
+                name.0
+            ));
         }
     }
-}
 
-#[cfg(not(feature = "enable"))]
-fn to_svg(art: &str) -> String {
     use std::fmt::Write;
-    use unicode_width::UnicodeWidthStr;
 
-    let lines = art.lines();
-    let cols = lines
-        .clone()
-        .map(|line| line.width())
-        .fold(0, std::cmp::max);
-    let rows = lines.clone().count();
+    // The CSS declarations applied to the image element
+    let mut style = String::new();
+    if options.border {
+        style += "border:1px solid rgba(128,128,128,0.5);padding:4px;";
+    }
+    if let Some(image_rendering) = &options.image_rendering {
+        // Keeps the edges sharp when the image is scaled
+        write!(style, "image-rendering:{};", image_rendering.0).unwrap();
+    }
 
-    let col_width = 8;
-    let width = cols * col_width;
-    let height = rows * 16;
+    // The CSS declarations applied to the `<div>` wrapping the image element
+    let mut wrapper_style = String::new();
+    if let Some(margin) = &options.margin {
+        write!(wrapper_style, "margin:{};", escape_attr(margin)).unwrap();
+    }
+    if options.scroll {
+        // Keep the image at its natural size, which is otherwise limited to
+        // the width of the content column by rustdoc's stylesheet
+        wrapper_style += "overflow-x:auto;";
+        style += "max-width:none;";
+    }
 
-    let mut content = String::new();
-    for (i, line) in lines.enumerate() {
-        let mut x = 0;
-        let y = i * 16 + 12;
-        let mut last_i = 0;
+    let alt = match (&options.alt, &options.alt_template) {
+        (Some(alt), _) => alt.clone(),
+        (None, Some(template)) => template.replace("{n}", &index.to_string()),
+        (None, None) => String::new(),
+    };
+    let alt = match &options.alt_prefix {
+        // An empty alternative text marks the image as decorative
+        Some(prefix) if !alt.is_empty() => format!("{}{}", prefix, alt),
+        _ => alt,
+    };
 
-        // Divide `line` by whitespace so that each text span is positioned
-        // precisely at their endpoints
-        split_whitespace_indices(line, |span, start_i| {
-            x += line[last_i..start_i].width() * col_width;
-            last_i = start_i;
+    if options.defer {
+        if params.label.is_some() {
+            return Err("`defer` can't be used with a link label".to_owned());
+        }
+        let out_dir = options
+            .out_dir
+            .as_deref()
+            .ok_or("`defer` requires `out-dir`")?;
+
+        // A later pass finds the art by the hash in the marker
+        let hash = content_hash(art.as_bytes());
+        write_out_file(art, out_dir, &format!("{:016x}.txt", hash))?;
+        write!(output, "<!-- svgbob-defer: {:016x} -->", hash).unwrap();
+        return Ok(());
+    }
+
+    if options.ascii {
+        if params.label.is_some() {
+            return Err("`ascii` can't be used with a link label".to_owned());
+        }
+
+        let start = output.len();
+        write_ascii(output, art, &alt, &style);
+
+        if !wrapper_style.is_empty() {
+            wrap_with_div(output, start, &wrapper_style);
+        }
+        if options.literal_safe {
+            make_literal_safe(output, start);
+        }
+        return Ok(());
+    }
+
+    if options
+        .scale
+        .map_or(false, |scale| !(scale > 0.0 && scale.is_finite()))
+    {
+        return Err("`scale` must be a positive number".to_owned());
+    }
+
+    if options
+        .dpi
+        .map_or(false, |dpi| !(dpi > 0.0 && dpi.is_finite()))
+    {
+        return Err("`dpi` must be a positive number".to_owned());
+    }
+
+    // `<use>` can only reference elements in the same document
+    let shared = options.id.is_some() || options.ref_id.is_some();
+    if shared && options.encoding != Encoding::InlineSvg {
+        return Err("`id` and `ref` require `encoding=inline-svg`".to_owned());
+    }
+
+    let svg_code = if let Some(ref_id) = &options.ref_id {
+        let symbol = symbols
+            .iter()
+            .find(|symbol| symbol.id == ref_id.0)
+            .ok_or_else(|| format!("no preceding diagram has `id={}`", ref_id.0))?;
+        format!("{}<use href=\"#{}\"/></svg>", symbol.start_tag, symbol.id)
+    } else {
+        let mut svg_code = render_svg(art, options, params.min_width, warnings);
+        if let Some(id) = &options.id {
+            define_symbol(&mut svg_code, &id.0, symbols)?;
+        }
+        svg_code
+    };
+
+    let warn_width = options.warn_width.unwrap_or(DEFAULT_WARN_WIDTH);
+    if warn_width > 0 && !options.scroll && options.ref_id.is_none() {
+        // Widths in other units than pixels (`unit`, `width`) aren't checked
+        let width = root_attr(&svg_code, "width").and_then(|width| width.parse::<f32>().ok());
+        if let Some(width) = width.filter(|&width| width > warn_width as f32) {
+            warnings.push(format!(
+                "svgbob diagram is {}px wide, which is wider than the content \
+                 column ({}px) and will be shrunk; consider `scroll`",
+                width.ceil(),
+                warn_width
+            ));
+        }
+    }
+
+    let mut svg_code = svg_code;
+    if options.encoding == Encoding::InlineSvg && root_attr(&svg_code, "id").is_none() {
+        // The ID only changes with the diagram, so it can be used as an anchor
+        // or a cache key
+        let id = format!(" id=\"svgbob-{:016x}\"", content_hash(svg_code.as_bytes()));
+        add_root_attrs(&mut svg_code, &id);
+    }
+    if options.encoding == Encoding::InlineSvg {
+        // The style sheets of inline SVG code apply to the whole page
+        let id = root_attr(&svg_code, "id").expect("no ID").to_owned();
+        scope_style_sheets(&mut svg_code, &id);
+    }
+
+    // A data URI, or SVG markup if `inline_svg` is set
+    let mut inline_svg = false;
+    let mut uri = match options.encoding {
+        Encoding::Base64 => format!("data:image/svg+xml;base64,{}", base64::encode(&*svg_code)),
+        Encoding::Percent => format!("data:image/svg+xml,{}", percent_encode(&svg_code)),
+        Encoding::Auto => {
+            let base64 = format!("data:image/svg+xml;base64,{}", base64::encode(&*svg_code));
+            let percent = format!("data:image/svg+xml,{}", percent_encode(&svg_code));
+            std::cmp::min_by_key(percent, base64, String::len)
+        }
+        Encoding::InlineSvg => {
+            inline_svg = true;
+            // A blank line would end the HTML block
+            svg_code.replace(|ch| ch == '\r' || ch == '\n', " ")
+        }
+    };
+
+    if options.fallback {
+        if options.encoding != Encoding::InlineSvg {
+            return Err("`fallback` requires `encoding=inline-svg`".to_owned());
+        }
+        if options.ref_id.is_some() {
+            // The image can't reference the symbol
+            return Err("`fallback` can't be used with `ref`".to_owned());
+        }
+    }
+
+    if options.figure && (params.label.is_some() || options.link_svg) {
+        return Err("`figure` can't be used with a link label or `link-svg`".to_owned());
+    }
+
+    if options.link_svg {
+        if options.out_dir.is_none() {
+            return Err("`link-svg` requires `out-dir`".to_owned());
+        }
+        if shared {
+            return Err("`link-svg` can't be used with `id` or `ref`".to_owned());
+        }
+        if options.object {
+            return Err("`link-svg` can't be used with `object`".to_owned());
+        }
+    }
+
+    let write_out = !shared && !options.dry_run;
+    if let Some(out_dir) = options.out_dir.as_ref().filter(|_| write_out) {
+        // Small images are cheaper to embed than to fetch, but a link needs a
+        // file to open
+        if uri.len() > options.inline_limit || options.link_svg {
+            uri = write_image(&svg_code, out_dir, options.url_prefix.as_deref())?;
+            inline_svg = false;
+        }
+    }
+
+    // Output the SVG as an image element
+    let start = output.len();
+    let references_start = references.len();
+    let has_label = params.label.is_some();
+    // Link reference definitions and Markdown links can't be wrapped
+    let wrap = !wrapper_style.is_empty() && !has_label && !options.link_svg;
+    if options.object {
+        if params.label.is_some() {
+            return Err("`object` can't be used with a link label".to_owned());
+        }
+        if options.encoding == Encoding::InlineSvg {
+            return Err("`object` can't be used with `encoding=inline-svg`".to_owned());
+        }
+
+        // The alternative text is displayed if the image can't be loaded
+        output.push_str("<object type='image/svg+xml'");
+        write!(output, " data='{}'", escape_attr(&uri)).unwrap();
+        if !style.is_empty() {
+            write!(output, " style='{}'", style).unwrap();
+        }
+        write!(output, ">{}</object>", escape_attr(&alt)).unwrap();
+    } else if inline_svg {
+        if params.label.is_some() {
+            return Err("`encoding=inline-svg` can't be used with a link label".to_owned());
+        }
+        if options.literal_safe {
+            // The character references would break the markup
+            return Err("`encoding=inline-svg` can't be used with `literal-safe`".to_owned());
+        }
+
+        // `<div>` starts an HTML block, whose content isn't processed as
+        // Markdown
+        output.push_str("<div");
+        if !alt.is_empty() {
+            write!(output, " role='img' aria-label='{}'", escape_attr(&alt)).unwrap();
+        }
+        if !style.is_empty() {
+            write!(output, " style='{}'", style).unwrap();
+        }
+        write!(output, ">{}</div>", uri).unwrap();
 
+        if options.fallback {
+            // Replace the markup with an image if scripts are disabled
+            let id = root_attr(&svg_code, "id").expect("no ID");
             write!(
-                content,
-                r#"<text x="{}" y="{}" textLength="{}">"#,
-                x,
-                y,
-                span.width() * col_width,
+                output,
+                "<noscript><style>#{}{{display:none}}</style>\
+                 <img src='data:image/svg+xml;base64,{}' alt='{}'></noscript>",
+                id,
+                base64::encode(&*svg_code),
+                escape_attr(&alt)
             )
             .unwrap();
-            escape_html(span, &mut content);
-            content.push_str("</text>");
-        });
+        }
+        output.push('\n');
+    } else if let Some(label) = &params.label {
+        if !style.is_empty() || !wrapper_style.is_empty() {
+            warnings
+                .push("styling options have no effect on diagrams with a link label".to_owned());
+        }
+        if options.alt.is_some() {
+            // The alternative text is given by `![alt][label]`
+            warnings.push("`alt` has no effect on diagrams with a link label".to_owned());
+        }
+        writeln!(output, "\n[{}]: {}", label, escape_destination(&uri)).unwrap();
+    } else if options.link_svg {
+        if !style.is_empty() || !wrapper_style.is_empty() {
+            warnings.push("styling options have no effect on diagrams with `link-svg`".to_owned());
+        }
+        let text = if alt.is_empty() { "diagram" } else { &alt };
+        write!(
+            output,
+            "[{}]({})",
+            escape_markdown(text, options.literal_safe),
+            escape_destination(&uri)
+        )
+        .unwrap();
+    } else if style.is_empty() && wrapper_style.is_empty() && !options.figure {
+        if options.reference_links {
+            // Labels don't collide with the ones defined by other doc comments
+            // of the same item, and identical images share a definition
+            let label = format!("svgbob-diagram-{:016x}", content_hash(uri.as_bytes()));
+            let alt = escape_markdown(&alt, options.literal_safe);
+            write!(output, "![{}][{}]", alt, label).unwrap();
+
+            let definition = format!("[{}]: {}\n", label, escape_destination(&uri));
+            if !references.contains(&definition) {
+                references.push_str(&definition);
+            }
+            if options.literal_safe {
+                make_literal_safe(references, references_start);
+            }
+        } else {
+            write!(
+                output,
+                "![{}]({})",
+                escape_markdown(&alt, options.literal_safe),
+                escape_destination(&uri)
+            )
+            .unwrap();
+        }
+    } else {
+        // Markdown images can't be styled, and aren't processed in the HTML
+        // block started by the wrapper or the figure
+        write!(
+            output,
+            "<img src='{}' alt='{}'",
+            escape_attr(&uri),
+            escape_attr(&alt)
+        )
+        .unwrap();
+        if !style.is_empty() {
+            write!(output, " style='{}'", style).unwrap();
+        }
+        output.push('>');
     }
 
-    fn split_whitespace_indices(mut s: &str, mut f: impl FnMut(&str, usize)) {
-        // Skip the the first whitespace characters
-        let s_trimmed = s.trim_start();
-        let mut offset = s.len() - s_trimmed.len();
-        s = s_trimmed;
-        while !s.is_empty() {
-            // Find the first whitespace character
-            let i = s
-                .char_indices()
-                .find(|(_, c)| c.is_whitespace())
-                .map(|(i, _)| i);
+    if options.dry_run {
+        // The checks above have passed. Replace the image with the art, which
+        // is much smaller.
+        output.truncate(start);
+        references.truncate(references_start);
+        if let Some(label) = &params.label {
+            writeln!(output, "\n[{}]: {}", label, DRY_RUN_PLACEHOLDER).unwrap();
+        } else {
+            write_ascii(output, art, &alt, &style);
+        }
+    } else if options.with_source && !has_label && options.ref_id.is_none() {
+        append_source(output, start, art);
+    }
 
-            // Emit a span comprised of non-whitespace characters
-            {
-                let i = i.unwrap_or(s.len());
-                let part = &s[..i];
-                f(part, offset);
-                offset += i;
-                s = &s[i..];
-            }
+    if wrap {
+        wrap_with_div(output, start, &wrapper_style);
+    }
+    if options.literal_safe {
+        // Escapes the URLs and the HTML markup. Texts escaped by
+        // `escape_markdown` are left as they are.
+        make_literal_safe(output, start);
+    }
 
-            // Skip the subsequent whitespace characters
-            let s_trimmed = s.trim_start();
-            offset += s.len() - s_trimmed.len();
-            s = s_trimmed;
+    Ok(())
+}
+
+/// Write the art as text in a `<pre>` element.
+fn write_ascii(output: &mut String, art: &str, alt: &str, style: &str) {
+    use std::fmt::Write;
+
+    // `<pre>` starts an HTML block, which ends at the line containing
+    // `</pre>`. The art is kept on a single line so that it doesn't break the
+    // enclosing block quote.
+    output.push_str("<pre class='svgbob-ascii'");
+    if !alt.is_empty() {
+        write!(output, " role='img' aria-label='{}'", escape_attr(alt)).unwrap();
+    }
+    write!(
+        output,
+        " style='background:rgba(128,128,128,0.1);{}'>{}</pre>",
+        style,
+        escape_attr(art).replace('\n', "&#10;")
+    )
+    .unwrap();
+}
+
+/// Append the art as a collapsed `<details>` element to the element in
+/// `output[start..]`. A trailing line break is kept at the end.
+fn append_source(output: &mut String, start: usize, art: &str) {
+    let end = start + output[start..].trim_end_matches('\n').len();
+    // Kept on a single line like `write_ascii`'s output
+    let details = format!(
+        "<details class='svgbob-source'><summary>Source</summary><pre>{}</pre></details>",
+        escape_attr(art).replace('\n', "&#10;")
+    );
+    output.insert_str(end, &details);
+}
+
+/// Wrap the element in `output[start..]` with a `<div>` having the specified
+/// `style`. A trailing line break is kept outside the wrapper.
+fn wrap_with_div(output: &mut String, start: usize, style: &str) {
+    let end = start + output[start..].trim_end_matches('\n').len();
+    output.insert_str(end, "</div>");
+    output.insert_str(start, &format!("<div style='{}'>", style));
+}
+
+/// Wrap the element in `output[start..]` with a `<figure>` captioned "Figure
+/// `number`.", which is also the figure's accessible name. A trailing line
+/// break is kept outside the figure.
+fn wrap_with_figure(output: &mut String, start: usize, number: usize) {
+    let end = start + output[start..].trim_end_matches('\n').len();
+    let caption = format!("Figure {}.", number);
+    // Derived from the content like the IDs of inline SVG code
+    let id = format!(
+        "svgbob-caption-{:016x}",
+        content_hash(format!("{}{}", caption, &output[start..end]).as_bytes())
+    );
+    output.insert_str(
+        end,
+        &format!("<figcaption id='{}'>{}</figcaption></figure>", id, caption),
+    );
+    output.insert_str(
+        start,
+        &format!("<figure role='figure' aria-labelledby='{}'>", id),
+    );
+}
+
+/// Render a diagram and apply the options affecting the SVG code. The image
+/// is widened to `min_width` pixels if it's narrower. Potential problems are
+/// appended to `warnings`.
+fn render_svg(
+    art: &str,
+    options: &DiagramOptions,
+    min_width: Option<f32>,
+    warnings: &mut Vec<String>,
+) -> String {
+    let mut svg_code = to_svg(art, options);
+    if options.fit == Fit::Tight {
+        if min_width.is_some() {
+            // Cropping would undo the alignment
+            warnings.push("`fit=tight` has no effect on diagrams in a `group`".to_owned());
+        } else {
+            fit_tight(&mut svg_code, options);
         }
     }
+    apply_svg_options(svg_code, art, options, min_width, warnings)
+}
 
-    fn escape_html(mut s: &str, out: &mut String) {
-        loop {
-            let i = s
-                .as_bytes()
+/// Find the width of the image of a diagram in a `group` in pixels, before
+/// it's widened to match the others. Returns `0` if the diagram can't be
+/// prepared, which is reported when it's converted.
+fn measure_width(art: &str, params: &CodeBlockParams) -> f32 {
+    let mut warnings = Vec::new();
+    match prepare_art(art, params, &mut warnings) {
+        Ok(art) => {
+            // Rendered like a diagram in a group, but not widened
+            let svg_code = render_svg(&art, &params.options, Some(0.0), &mut warnings);
+            root_view_box(&svg_code)[2]
+        }
+        Err(_) => 0.0,
+    }
+}
+
+/// Apply the options affecting the SVG code to the rendered diagram `art`,
+/// and widen the image to `min_width` pixels if it's narrower. Potential
+/// problems are appended to `warnings`.
+fn apply_svg_options(
+    mut svg_code: String,
+    art: &str,
+    options: &DiagramOptions,
+    min_width: Option<f32>,
+    warnings: &mut Vec<String>,
+) -> String {
+    use std::fmt::Write;
+
+    hoist_markers(&mut svg_code);
+
+    if options.debug_grid {
+        add_debug_grid(&mut svg_code, art, options);
+    }
+    if options.selectable {
+        add_text_layer(&mut svg_code, art, options);
+    }
+
+    // Attributes added to the root element (` name="value"`)
+    let mut root_attrs = String::new();
+    if let Some(font_weight) = &options.font_weight {
+        // Inherited by `<text>` elements
+        write!(root_attrs, " font-weight=\"{}\"", font_weight.0).unwrap();
+    }
+    if let Some(label) = &options.a11y {
+        write!(
+            root_attrs,
+            " role=\"img\" aria-label=\"{}\"",
+            escape_attr(label)
+        )
+        .unwrap();
+    }
+    if let Some(lang) = &options.xml_lang {
+        // `lang` is for HTML documents (inline SVG code), and `xml:lang` is
+        // for standalone SVG documents
+        write!(root_attrs, " lang=\"{0}\" xml:lang=\"{0}\"", lang.0).unwrap();
+    }
+    add_root_attrs(&mut svg_code, &root_attrs);
+
+    if options.size_properties {
+        let properties = format!(
+            "--dw:{}px;--dh:{}px",
+            root_attr(&svg_code, "width").unwrap_or("0"),
+            root_attr(&svg_code, "height").unwrap_or("0")
+        );
+        let style = match root_attr(&svg_code, "style") {
+            Some(style) => format!("{};{}", style.trim_end_matches(';'), properties),
+            None => properties,
+        };
+        set_root_attr(&mut svg_code, "style", &style);
+    }
+
+    const SHAPES: &[&str] = &["line", "path", "circle", "rect", "polygon", "polyline"];
+
+    // Rules overriding svgbob's stylesheet (selectors and declarations)
+    let mut rules: Vec<(&[&str], String)> = Vec::new();
+
+    // Declarations applied to shapes
+    let mut shape_style = String::new();
+    if let Some(linecap) = &options.stroke_linecap {
+        write!(shape_style, "stroke-linecap:{};", linecap.0).unwrap();
+    }
+    if let Some(linejoin) = &options.stroke_linejoin {
+        write!(shape_style, "stroke-linejoin:{};", linejoin.0).unwrap();
+    }
+    if !shape_style.is_empty() {
+        rules.push((SHAPES, shape_style));
+    }
+
+    let colors = options
+        .theme
+        .as_ref()
+        .and_then(|theme| theme.colors(options.theme_presets.as_ref()));
+    if let Some(colors) = colors {
+        rules.push((SHAPES, format!("stroke:{};", colors.stroke)));
+        rules.push((&[".filled"], format!("fill:{};", colors.stroke)));
+        rules.push((&["text"], format!("fill:{};", colors.text)));
+        // Follows the shape rule, which would give the backdrop a stroke
+        rules.push((
+            &["rect.backdrop", ".bg_filled", ".nofill"],
+            format!("stroke:none;fill:{};", colors.background),
+        ));
+    }
+
+    if let Some(gradient) = &options.fill_gradient {
+        // The ID is shared by the diagrams using the same gradient, whose
+        // definitions are identical
+        let id = format!(
+            "svgbob-gradient-{:016x}",
+            content_hash(format!("{},{}", gradient.from, gradient.to).as_bytes())
+        );
+        let def = format!(
+            "<linearGradient id=\"{}\" x1=\"0\" y1=\"0\" x2=\"0\" y2=\"1\">\
+             <stop offset=\"0\" stop-color=\"{}\"/><stop offset=\"1\" stop-color=\"{}\"/>\
+             </linearGradient>",
+            id, gradient.from, gradient.to
+        );
+        add_def(&mut svg_code, &def);
+        // Follows the theme rule for `.filled`
+        rules.push((&[".filled"], format!("fill:url(#{});", id)));
+        // The placeholder image has no shapes
+        if !has_class(&svg_code, "filled") && cfg!(feature = "enable") {
+            warnings
+                .push("`fill-gradient` has no effect on diagrams without filled shapes".to_owned());
+        }
+    }
+
+    if options.shadow {
+        // The definitions are identical in every diagram, so the ID is shared
+        add_def(
+            &mut svg_code,
+            "<filter id=\"svgbob-shadow\" x=\"-20%\" y=\"-20%\" width=\"140%\" height=\"140%\">\
+             <feDropShadow dx=\"1\" dy=\"1\" stdDeviation=\"1\" flood-opacity=\"0.4\"/>\
+             </filter>",
+        );
+        // Not applied to the backdrop, which would cover the shadows of the
+        // rest
+        rules.push((
+            &[
+                "line",
+                "path",
+                "circle",
+                "rect:not(.backdrop)",
+                "polygon",
+                "polyline",
+                "text",
+            ],
+            "filter:url(#svgbob-shadow);".to_owned(),
+        ));
+        if find_backdrop(&svg_code).is_none() && cfg!(feature = "enable") {
+            warnings.push(
+                "svgbob's backdrop wasn't found in the image, so `shadow` may be cast \
+                 from it"
+                    .to_owned(),
+            );
+        }
+    }
+
+    if !rules.is_empty() {
+        // The style sheet of inline SVG code applies to the whole page, so
+        // the selectors are scoped by a class named after the declarations
+        let key: String = rules.iter().map(|(_, decls)| &**decls).collect();
+        let class = format!("svgbob-{:016x}", content_hash(key.as_bytes()));
+        let classes = match root_attr(&svg_code, "class") {
+            Some(classes) => format!("{} {}", classes, class),
+            None => class.clone(),
+        };
+        set_root_attr(&mut svg_code, "class", &classes);
+
+        // Have higher specificities than svgbob's `line, path, ...`
+        let mut sheet = "<style>".to_owned();
+        for (selectors, decls) in rules.iter() {
+            let selectors: Vec<String> = selectors
                 .iter()
-                .position(|b| matches!(b, b'<' | b'>' | b'&' | 0));
-            out.push_str(&s[..i.unwrap_or(s.len())]);
-            if let Some(i) = i {
-                out.push_str(match s.as_bytes()[i] {
-                    b'<' => "&lt;",
-                    b'>' => "&gt;",
-                    b'&' => "&amp;",
-                    0 => " ",
-                    _ => unreachable!(),
-                });
-                s = &s[i + 1..];
-            } else {
-                break;
+                .map(|selector| format!(".{} {}", class, selector))
+                .collect();
+            write!(sheet, "{}{{{}}}", selectors.join(","), decls).unwrap();
+        }
+        sheet += "</style>";
+        let end = svg_code.rfind("</svg>").expect("root element not closed");
+        svg_code.insert_str(end, &sheet);
+    }
+
+    if let Some(aspect) = options.aspect {
+        // Extend the shorter side and center the diagram
+        let attr = |name| -> f32 {
+            root_attr(&svg_code, name)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0.0)
+        };
+        let (width, height) = (attr("width"), attr("height"));
+        let [x, y, _, _] = root_view_box(&svg_code);
+        let ratio = aspect.width / aspect.height;
+        let (new_width, new_height) = if width < height * ratio {
+            (height * ratio, height)
+        } else {
+            (width, width / ratio)
+        };
+        let view_box = format!(
+            "{} {} {} {}",
+            x + (width - new_width) / 2.0,
+            y + (height - new_height) / 2.0,
+            new_width,
+            new_height
+        );
+
+        set_root_attr(&mut svg_code, "viewBox", &view_box);
+        set_root_attr(&mut svg_code, "width", &new_width.to_string());
+        set_root_attr(&mut svg_code, "height", &new_height.to_string());
+        set_root_attr(&mut svg_code, "preserveAspectRatio", "xMidYMid meet");
+    }
+
+    if options.card && !add_card(&mut svg_code) && cfg!(feature = "enable") {
+        warnings
+            .push("svgbob's backdrop wasn't found in the image, so it may hide `card`".to_owned());
+    }
+
+    if let Some(min_width) = min_width {
+        // After every other change to the size in pixels so that the images
+        // in a group end up equally wide
+        widen(&mut svg_code, min_width);
+    }
+
+    if let Some(palette) = options.palette {
+        apply_palette(&mut svg_code, palette.colors());
+    }
+
+    let dpi = options.dpi.unwrap_or(DEFAULT_DPI);
+    let unit = match options.unit {
+        Unit::Px => None,
+        Unit::Em => Some((1.0 / DIAGRAM_FONT_SIZE as f32, "em")),
+        Unit::Mm => Some((25.4 / dpi, "mm")),
+        Unit::In => Some((1.0 / dpi, "in")),
+    };
+    if let Some((per_px, suffix)) = unit {
+        // Keep the coordinate system in pixels
+        let width = root_attr(&svg_code, "width").unwrap_or("0").to_owned();
+        let height = root_attr(&svg_code, "height").unwrap_or("0").to_owned();
+        if root_attr(&svg_code, "viewBox").is_none() {
+            let view_box = format!(" viewBox=\"0 0 {} {}\"", width, height);
+            add_root_attrs(&mut svg_code, &view_box);
+        }
+
+        for (name, value) in [("width", width), ("height", height)].iter() {
+            let value: f32 = value.parse().unwrap_or(0.0);
+            let value = format!("{:.3}{}", value * per_px, suffix);
+            set_root_attr(&mut svg_code, name, &value);
+        }
+    }
+
+    if options.width.is_some() || options.height.is_some() {
+        // The coordinate system is scaled to the percentages
+        if root_attr(&svg_code, "viewBox").is_none() {
+            let [_, _, width, height] = root_view_box(&svg_code);
+            let view_box = format!(" viewBox=\"0 0 {} {}\"", width, height);
+            add_root_attrs(&mut svg_code, &view_box);
+        }
+
+        for (name, value) in [("width", options.width), ("height", options.height)].iter() {
+            match value {
+                Some(percentage) => {
+                    set_root_attr(&mut svg_code, name, &format!("{}%", percentage.0))
+                }
+                // Follow the aspect ratio of `viewBox`
+                None => remove_root_attr(&mut svg_code, name),
             }
         }
     }
 
-    format!(
-        include_str!("minimal_template.svg"),
-        font = DIAGRAM_FONT,
-        width = width,
-        height = height,
-        content = content,
-    )
+    svg_code
+}
+
+/// Scope the rules of the style sheets (`<style>`) in `svg_code` to the root
+/// element, whose ID is `id`. The selectors already scoped by a class of the
+/// root element (`.svgbob-...`) are combined with the ID so that they keep
+/// higher specificities than svgbob's.
+fn scope_style_sheets(svg_code: &mut String, id: &str) {
+    let mut i = 0;
+    while let Some(start) = svg_code[i..].find("<style").map(|k| i + k) {
+        let start = start + svg_code[start..].find('>').expect("unclosed tag") + 1;
+        let end = start
+            + svg_code[start..]
+                .find("</style>")
+                .expect("unclosed element");
+
+        let mut sheet = String::new();
+        let mut rest = &svg_code[start..end];
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..].find('}').map_or(rest.len(), |k| open + k + 1);
+            let selectors: Vec<String> = rest[..open]
+                .split(',')
+                .map(str::trim)
+                .map(|selector| {
+                    if selector.starts_with(".svgbob-") {
+                        format!("#{}{}", id, selector)
+                    } else {
+                        format!("#{} {}", id, selector)
+                    }
+                })
+                .collect();
+            sheet += &selectors.join(",");
+            sheet += &rest[open..close];
+            rest = &rest[close..];
+        }
+        sheet += rest;
+
+        svg_code.replace_range(start..end, &sheet);
+        i = start + sheet.len();
+    }
+}
+
+/// Replace the distinct hexadecimal colors (`#rgb` or `#rrggbb`) of strokes
+/// and fills with the colors in `palette` in the order of their first
+/// appearance, cycling through `palette` if there are more. Nearly gray colors
+/// are kept, as they differ in lightness rather than hue.
+fn apply_palette(svg_code: &mut String, palette: &[&str]) {
+    const PROPERTIES: &[&str] = &[
+        "stroke=\"",
+        "fill=\"",
+        "stop-color=\"",
+        "stroke:",
+        "fill:",
+        "stop-color:",
+    ];
+
+    /// Parse a hexadecimal color into the RGB components.
+    fn parse_color(s: &str) -> Option<[u8; 3]> {
+        let digits = s.strip_prefix('#')?;
+        let digit = |i: usize| u8::from_str_radix(&digits[i..i + 1], 16).ok();
+        match digits.len() {
+            3 => Some([digit(0)? * 17, digit(1)? * 17, digit(2)? * 17]),
+            6 => Some([
+                digit(0)? * 16 + digit(1)?,
+                digit(2)? * 16 + digit(3)?,
+                digit(4)? * 16 + digit(5)?,
+            ]),
+            _ => None,
+        }
+    }
+
+    // (original color, replacement)
+    let mut mapping: Vec<(String, &str)> = Vec::new();
+    let mut out = String::with_capacity(svg_code.len());
+    let mut rest = &svg_code[..];
+    while let Some((i, property)) = PROPERTIES
+        .iter()
+        .filter_map(|property| rest.find(property).map(|i| (i, property)))
+        .min()
+    {
+        let start = i + property.len();
+        let len = rest[start..]
+            .find(|ch: char| !(ch == '#' || ch.is_ascii_hexdigit()))
+            .unwrap_or(rest.len() - start);
+        let value = &rest[start..start + len];
+        out.push_str(&rest[..start]);
+        rest = &rest[start + len..];
+
+        let is_colorful = parse_color(value).map_or(false, |rgb| {
+            rgb.iter().max().unwrap() - rgb.iter().min().unwrap() >= 32
+        });
+        if !is_colorful {
+            out.push_str(value);
+            continue;
+        }
+
+        let value = value.to_ascii_lowercase();
+        let replacement = match mapping.iter().find(|(color, _)| *color == value) {
+            Some(&(_, replacement)) => replacement,
+            None => {
+                let replacement = palette[mapping.len() % palette.len()];
+                mapping.push((value, replacement));
+                replacement
+            }
+        };
+        out.push_str(replacement);
+    }
+    out.push_str(rest);
+    *svg_code = out;
+}
+
+/// Turn the content of the root element into a `<symbol>` element, which is
+/// displayed by a `<use>` element, so that later diagrams can reference it.
+fn define_symbol(
+    svg_code: &mut String,
+    id: &str,
+    symbols: &mut Vec<Symbol>,
+) -> std::result::Result<(), String> {
+    use std::fmt::Write;
+
+    if symbols.iter().any(|symbol| symbol.id == id) {
+        return Err(format!("duplicate diagram `id={}`", id));
+    }
+
+    let view_box = match root_attr(svg_code, "viewBox") {
+        Some(view_box) => view_box.to_owned(),
+        None => format!(
+            "0 0 {} {}",
+            root_attr(svg_code, "width").unwrap_or("0"),
+            root_attr(svg_code, "height").unwrap_or("0")
+        ),
+    };
+
+    let tag = root_start_tag(svg_code);
+    let start_tag = svg_code[tag.start..=tag.end].to_owned();
+    let content_end = svg_code.rfind("</svg>").expect("root element not closed");
+    let content = svg_code[tag.end + 1..content_end].to_owned();
+
+    svg_code.truncate(tag.end + 1);
+    write!(
+        svg_code,
+        "<symbol id=\"{}\" viewBox=\"{}\">{}</symbol><use href=\"#{}\"/></svg>",
+        id, view_box, content, id
+    )
+    .unwrap();
+
+    symbols.push(Symbol {
+        id: id.to_owned(),
+        start_tag,
+    });
+    Ok(())
+}
+
+/// Append a visible error message in place of a diagram that couldn't be
+/// converted.
+fn write_error_placeholder(output: &mut String, message: &str, literal_safe: bool) {
+    let start = output.len();
+    *output += "<pre class='svgbob-error' style='color:#c00'>svgbob error: ";
+    *output += &escape_attr(message).replace('\n', "&#10;");
+    *output += "</pre>";
+
+    if literal_safe {
+        make_literal_safe(output, start);
+    }
+}
+
+/// Replace the characters that need escaping in a string literal in
+/// `output[start..]` with character references, which Markdown decodes in link
+/// labels and destinations.
+fn make_literal_safe(output: &mut String, start: usize) {
+    let escaped = output[start..].replace('\\', "&#92;").replace('"', "&#34;");
+    output.truncate(start);
+    output.push_str(&escaped);
+}
+
+/// Find lines with texts extending past the right edges of the boxes drawn by
+/// the adjacent lines, e.g.:
+///
+/// ```text
+/// +-----+
+/// | long label |
+/// +-----+
+/// ```
+///
+/// Returns 1-based line numbers.
+fn find_overflowing_labels(art: &str) -> Vec<usize> {
+    use unicode_width::UnicodeWidthChar;
+
+    // Lay out characters in a grid. The second halves of wide characters are
+    // represented by `'\0'`.
+    let grid: Vec<Vec<char>> = art
+        .lines()
+        .map(|line| {
+            let mut cells = Vec::new();
+            for ch in line.chars() {
+                let width = ch.width().unwrap_or(0);
+                if width > 0 {
+                    cells.push(ch);
+                    cells.extend(std::iter::repeat('\0').take(width - 1));
+                }
+            }
+            cells
+        })
+        .collect();
+
+    let is_corner = |ch: char| matches!(ch, '+' | '.' | ',' | '\'' | '`');
+    let is_label = |ch: char| ch.is_alphanumeric() || ch == '\0';
+
+    let mut lines = Vec::new();
+    for (i, row) in grid.iter().enumerate() {
+        let edges = [i.checked_sub(1), Some(i + 1)];
+        let overflows = edges.iter().filter_map(|&k| grid.get(k?)).any(|edge| {
+            // Find a horizontal edge `+-----+` whose left end is connected to
+            // `|` on this line, and check if this line's next `|` is pushed
+            // out past the right end by a text
+            (0..edge.len()).filter(|&l| is_corner(edge[l])).any(|l| {
+                let r = match (l + 1..edge.len()).find(|&k| edge[k] != '-') {
+                    Some(r) if r > l + 1 && is_corner(edge[r]) => r,
+                    _ => return false,
+                };
+                if row.get(l) != Some(&'|') {
+                    return false;
+                }
+                match (l + 1..row.len()).find(|&k| row[k] == '|') {
+                    Some(bar) if bar > r => row[r..bar].iter().any(|&ch| is_label(ch)),
+                    _ => false,
+                }
+            })
+        });
+        if overflows {
+            lines.push(i + 1);
+        }
+    }
+    lines
+}
+
+/// Find the root element's start tag (`<svg ...>`) in `svg_code`, excluding
+/// the closing `>` or `/>`.
+fn root_start_tag(svg_code: &str) -> std::ops::Range<usize> {
+    let start = svg_code.find("<svg").expect("root element not found");
+    let mut end = start + svg_code[start..].find('>').unwrap();
+    if svg_code[..end].ends_with('/') {
+        end -= 1; // `<svg ... />`
+    }
+    start..end
+}
+
+/// Get the value of the root element's attribute.
+fn root_attr<'a>(svg_code: &'a str, name: &str) -> Option<&'a str> {
+    root_attr_range(svg_code, name).map(|range| &svg_code[range])
+}
+
+fn root_attr_range(svg_code: &str, name: &str) -> Option<std::ops::Range<usize>> {
+    let tag = root_start_tag(svg_code);
+    let prefix = format!(" {}=\"", name);
+    let start = tag.start + svg_code[tag.clone()].find(&prefix)? + prefix.len();
+    let end = start + svg_code[start..tag.end].find('"')?;
+    Some(start..end)
+}
+
+/// Set the value of the root element's attribute.
+fn set_root_attr(svg_code: &mut String, name: &str, value: &str) {
+    if let Some(range) = root_attr_range(svg_code, name) {
+        svg_code.replace_range(range, value);
+    } else {
+        add_root_attrs(svg_code, &format!(" {}=\"{}\"", name, value));
+    }
+}
+
+/// Remove the root element's attribute if it exists.
+fn remove_root_attr(svg_code: &mut String, name: &str) {
+    if let Some(range) = root_attr_range(svg_code, name) {
+        // ` name="value"`
+        let start = range.start - format!(" {}=\"", name).len();
+        svg_code.replace_range(start..range.end + 1, "");
+    }
+}
+
+/// Insert `attrs` (` name="value"`) at the end of the root element's start
+/// tag.
+fn add_root_attrs(svg_code: &mut String, attrs: &str) {
+    let end = root_start_tag(svg_code).end;
+    svg_code.insert_str(end, attrs);
+}
+
+/// Overlay lines along the boundaries of the character cells of `art` on the
+/// image.
+fn add_debug_grid(svg_code: &mut String, art: &str, options: &DiagramOptions) {
+    use std::fmt::Write;
+    use unicode_width::UnicodeWidthStr;
+
+    let cols = art.lines().map(|line| line.width()).fold(0, std::cmp::max);
+    let rows = art.lines().count();
+
+    let col_width = options.scale.unwrap_or(DEFAULT_SCALE);
+    let row_height = col_width * 2.0;
+    let width = cols as f32 * col_width;
+    let height = rows as f32 * row_height;
+
+    let mut path = String::new();
+    for i in 0..=cols {
+        write!(path, "M{} 0V{}", i as f32 * col_width, height).unwrap();
+    }
+    for i in 0..=rows {
+        write!(path, "M0 {}H{}", i as f32 * row_height, width).unwrap();
+    }
+
+    let end = svg_code.rfind("</svg>").expect("root element not closed");
+    svg_code.insert_str(
+        end,
+        &format!(
+            "<path d=\"{}\" fill=\"none\" stroke=\"rgba(255,0,0,0.25)\" stroke-width=\"0.5\"/>",
+            path
+        ),
+    );
+}
+
+/// Get the root element's `viewBox` (`[x, y, width, height]`), which defaults
+/// to the root element's size.
+fn root_view_box(svg_code: &str) -> [f32; 4] {
+    let attr = |name| -> f32 {
+        root_attr(svg_code, name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0.0)
+    };
+    let view_box: Vec<f32> = root_attr(svg_code, "viewBox")
+        .map(|view_box| {
+            view_box
+                .split_whitespace()
+                .filter_map(|x| x.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    match view_box[..] {
+        [x, y, width, height] => [x, y, width, height],
+        _ => [0.0, 0.0, attr("width"), attr("height")],
+    }
+}
+
+/// Extend the right edge of the image so that it's at least `min_width`
+/// pixels wide.
+fn widen(svg_code: &mut String, min_width: f32) {
+    let [x, y, width, height] = root_view_box(svg_code);
+    if width >= min_width {
+        return;
+    }
+    set_root_attr(
+        svg_code,
+        "viewBox",
+        &format!("{} {} {} {}", x, y, min_width, height),
+    );
+    set_root_attr(svg_code, "width", &min_width.to_string());
+}
+
+/// The space kept around the drawn shapes by `fit_tight`, measured in
+/// pixels. This covers the strokes and the markers, which extend past the
+/// coordinates of the shapes.
+const FIT_MARGIN: f32 = 2.0;
+
+/// Crop the image to the bounding box of the shapes and texts drawn in it,
+/// plus `FIT_MARGIN`. The image is never extended.
+fn fit_tight(svg_code: &mut String, options: &DiagramOptions) {
+    let [x, y, width, height] = root_view_box(svg_code);
+    let row_height = options.scale.unwrap_or(DEFAULT_SCALE) * 2.0;
+    let [left, top, right, bottom] = match drawn_bounds(svg_code, row_height) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let left = (left - FIT_MARGIN).max(x);
+    let top = (top - FIT_MARGIN).max(y);
+    let right = (right + FIT_MARGIN).min(x + width);
+    let bottom = (bottom + FIT_MARGIN).min(y + height);
+    if right <= left || bottom <= top {
+        return;
+    }
+
+    let (width, height) = (right - left, bottom - top);
+    set_root_attr(
+        svg_code,
+        "viewBox",
+        &format!("{} {} {} {}", left, top, width, height),
+    );
+    set_root_attr(svg_code, "width", &width.to_string());
+    set_root_attr(svg_code, "height", &height.to_string());
+}
+
+/// Find the bounding box (`[left, top, right, bottom]`) of the shapes and
+/// texts in `svg_code`, excluding the backdrop and definitions. A text is
+/// assumed to occupy a row of `row_height` pixels whose baseline is at three
+/// quarters of the row.
+fn drawn_bounds(svg_code: &str, row_height: f32) -> Option<[f32; 4]> {
+    /// Elements whose content isn't drawn where it's placed
+    const SKIPPED: &[&str] = &["defs", "style", "marker", "symbol", "title", "desc"];
+
+    let mut bounds: Option<[f32; 4]> = None;
+    let mut add = |x: f32, y: f32| {
+        if !(x.is_finite() && y.is_finite()) {
+            return;
+        }
+        bounds = Some(match bounds {
+            None => [x, y, x, y],
+            Some([l, t, r, b]) => [l.min(x), t.min(y), r.max(x), b.max(y)],
+        });
+    };
+
+    let mut offset = 0;
+    while let Some(i) = svg_code[offset..].find('<') {
+        let start = offset + i;
+        let tag_end = match svg_code[start..].find('>') {
+            Some(k) => start + k + 1,
+            None => break,
+        };
+        let tag = &svg_code[start..tag_end];
+        offset = tag_end;
+        let name = tag[1..]
+            .split(|ch: char| ch.is_ascii_whitespace() || ch == '>' || ch == '/')
+            .next()
+            .unwrap_or("");
+        if SKIPPED.contains(&name) && !tag.ends_with("/>") {
+            let end_tag = format!("</{}>", name);
+            match svg_code[offset..].find(&end_tag) {
+                Some(k) => offset += k + end_tag.len(),
+                None => break,
+            }
+            continue;
+        }
+
+        let attr = |name: &str| -> Option<&str> {
+            let prefix = format!(" {}=\"", name);
+            let start = tag.find(&prefix)? + prefix.len();
+            Some(&tag[start..start + tag[start..].find('"')?])
+        };
+        let num = |name: &str| -> f32 {
+            attr(name)
+                .and_then(|value| value.trim_end_matches("px").parse().ok())
+                .unwrap_or(0.0)
+        };
+        let mut add_points = |points: &str| {
+            let coords: Vec<f32> = points
+                .split(|ch: char| ch.is_ascii_whitespace() || ch == ',')
+                .filter_map(|x| x.parse().ok())
+                .collect();
+            for point in coords.chunks_exact(2) {
+                add(point[0], point[1]);
+            }
+        };
+
+        match name {
+            "line" => {
+                add(num("x1"), num("y1"));
+                add(num("x2"), num("y2"));
+            }
+            "rect" => {
+                if has_class(tag, "backdrop") {
+                    continue;
+                }
+                let (x, y) = (num("x"), num("y"));
+                add(x, y);
+                add(x + num("width"), y + num("height"));
+            }
+            "circle" | "ellipse" => {
+                let (rx, ry) = if name == "circle" {
+                    (num("r"), num("r"))
+                } else {
+                    (num("rx"), num("ry"))
+                };
+                add(num("cx") - rx, num("cy") - ry);
+                add(num("cx") + rx, num("cy") + ry);
+            }
+            "polyline" | "polygon" => add_points(attr("points").unwrap_or("")),
+            "path" => path_points(attr("d").unwrap_or(""), &mut add),
+            "text" => {
+                let content_end = svg_code[offset..]
+                    .find("</text>")
+                    .map_or(offset, |k| offset + k);
+                let content = &svg_code[offset..content_end];
+                if content.trim().is_empty() {
+                    continue;
+                }
+                let width = match attr("textLength") {
+                    Some(_) => num("textLength"),
+                    None => content.chars().count() as f32 * row_height / 2.0,
+                };
+                let x = num("x") + num("dx");
+                let left = match attr("text-anchor") {
+                    Some("middle") => x - width / 2.0,
+                    Some("end") => x - width,
+                    _ => x,
+                };
+                let baseline = num("y") + num("dy");
+                add(left, baseline - row_height * 0.75);
+                add(left + width, baseline + row_height * 0.25);
+            }
+            _ => {}
+        }
+    }
+    bounds
+}
+
+/// Call `f` with the points of the path data `d`: the endpoints of the
+/// segments and the control points of curves. Arcs are bounded by their
+/// endpoints, which holds for svgbob's arcs spanning at most a quarter turn.
+fn path_points(d: &str, f: &mut dyn FnMut(f32, f32)) {
+    let mut current = (0.0, 0.0);
+    let mut rest = d.trim_start();
+    while let Some(command) = rest.chars().next() {
+        rest = &rest[command.len_utf8()..];
+        let args_len = rest
+            .find(|ch: char| ch.is_ascii_alphabetic() && ch != 'e' && ch != 'E')
+            .unwrap_or(rest.len());
+        let mut args = Vec::new();
+        let mut arg = String::new();
+        for ch in rest[..args_len].chars() {
+            let starts_number = ch == '-' && !arg.ends_with(|ch| ch == 'e' || ch == 'E');
+            if ch.is_ascii_whitespace() || ch == ',' || (starts_number && !arg.is_empty()) {
+                args.extend(arg.parse::<f32>().ok());
+                arg.clear();
+            }
+            if !(ch.is_ascii_whitespace() || ch == ',') {
+                arg.push(ch);
+            }
+        }
+        args.extend(arg.parse::<f32>().ok());
+        rest = rest[args_len..].trim_start();
+
+        let relative = command.is_ascii_lowercase();
+        let (group_len, point_indices): (usize, &[usize]) = match command.to_ascii_uppercase() {
+            'M' | 'L' | 'T' => (2, &[0]),
+            'H' | 'V' => (1, &[0]),
+            'S' | 'Q' => (4, &[0, 2]),
+            'C' => (6, &[0, 2, 4]),
+            'A' => (7, &[5]),
+            _ => continue,
+        };
+        for group in args.chunks_exact(group_len) {
+            let base = if relative { current } else { (0.0, 0.0) };
+            let point = |i: usize| match command.to_ascii_uppercase() {
+                'H' => (base.0 + group[i], current.1),
+                'V' => (current.0, base.1 + group[i]),
+                _ => (base.0 + group[i], base.1 + group[i + 1]),
+            };
+            for &i in point_indices {
+                let (x, y) = point(i);
+                f(x, y);
+            }
+            current = point(*point_indices.last().unwrap());
+        }
+    }
+}
+
+/// The space between a diagram and the edges of the card drawn by `card`,
+/// measured in pixels.
+const CARD_PADDING: f32 = 8.0;
+
+/// Extend the image by `CARD_PADDING` on every side and draw a rounded
+/// rectangle filling it behind the diagram. Returns `false` if svgbob's
+/// backdrop isn't found, in which case the rectangle is placed before every
+/// other element.
+fn add_card(svg_code: &mut String) -> bool {
+    let attr = |svg_code: &str, name| -> f32 {
+        root_attr(svg_code, name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0.0)
+    };
+    let (width, height) = (attr(svg_code, "width"), attr(svg_code, "height"));
+    let view_box = root_view_box(svg_code);
+
+    let (x, y) = (view_box[0] - CARD_PADDING, view_box[1] - CARD_PADDING);
+    let (vb_width, vb_height) = (
+        view_box[2] + CARD_PADDING * 2.0,
+        view_box[3] + CARD_PADDING * 2.0,
+    );
+    let view_box = format!("{} {} {} {}", x, y, vb_width, vb_height);
+    set_root_attr(svg_code, "viewBox", &view_box);
+    set_root_attr(svg_code, "width", &(width + CARD_PADDING * 2.0).to_string());
+    set_root_attr(
+        svg_code,
+        "height",
+        &(height + CARD_PADDING * 2.0).to_string(),
+    );
+
+    // The stroke is inset by half its width so that it isn't clipped. The
+    // style attribute overrides svgbob's stylesheet for `rect`.
+    let card = format!(
+        "<rect class=\"svgbob-card\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"6\" \
+         style=\"fill:rgba(128,128,128,0.06);stroke:rgba(128,128,128,0.35);stroke-width:1\"/>",
+        x + 0.5,
+        y + 0.5,
+        vb_width - 1.0,
+        vb_height - 1.0
+    );
+    // Drawn over svgbob's opaque backdrop
+    let backdrop = find_backdrop(svg_code);
+    let end = match &backdrop {
+        Some(range) => range.end,
+        None => {
+            let root_end = root_start_tag(svg_code).end;
+            root_end + svg_code[root_end..].find('>').unwrap() + 1
+        }
+    };
+    svg_code.insert_str(end, &card);
+    backdrop.is_some()
+}
+
+/// Find svgbob's backdrop, the `<rect>` element of the class `backdrop`, and
+/// return its range in `svg_code`.
+fn find_backdrop(svg_code: &str) -> Option<std::ops::Range<usize>> {
+    const END_TAG: &str = "</rect>";
+
+    let mut offset = 0;
+    while let Some(i) = svg_code[offset..].find("<rect") {
+        let start = offset + i;
+        let tag_end = start + svg_code[start..].find('>')? + 1;
+        let tag = &svg_code[start..tag_end];
+        offset = tag_end;
+        let is_backdrop = tag.split(|ch: char| ch.is_ascii_whitespace()).any(|attr| {
+            attr.starts_with("class=\"backdrop\"") || attr.starts_with("class='backdrop'")
+        });
+        if !is_backdrop {
+            continue;
+        }
+        if tag.ends_with("/>") {
+            return Some(start..tag_end);
+        }
+        let end = tag_end + svg_code[tag_end..].find(END_TAG)? + END_TAG.len();
+        return Some(start..end);
+    }
+    None
+}
+
+/// Check if an element in `svg_code` is in `class`.
+fn has_class(svg_code: &str, class: &str) -> bool {
+    svg_code.match_indices("class=").any(|(i, _)| {
+        let value = &svg_code[i + 6..];
+        let quote = match value.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => return false,
+        };
+        let value = &value[1..];
+        value[..value.find(quote).unwrap_or(value.len())]
+            .split_ascii_whitespace()
+            .any(|name| name == class)
+    })
+}
+
+/// Overlay the lines of `art` as transparent texts on the image so that they
+/// can be selected and copied.
+fn add_text_layer(svg_code: &mut String, art: &str, options: &DiagramOptions) {
+    use std::fmt::Write;
+    use unicode_width::UnicodeWidthStr;
+
+    let col_width = options.scale.unwrap_or(DEFAULT_SCALE);
+    let row_height = col_width * 2.0;
+
+    let mut layer = "<g fill-opacity=\"0\" xml:space=\"preserve\">".to_owned();
+    for (i, line) in art.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        write!(
+            layer,
+            "<text x=\"0\" y=\"{}\" textLength=\"{}\">{}</text>",
+            (i as f32 + 0.75) * row_height,
+            line.width() as f32 * col_width,
+            escape_attr(line)
+        )
+        .unwrap();
+    }
+    layer += "</g>";
+
+    let end = svg_code.rfind("</svg>").expect("root element not closed");
+    svg_code.insert_str(end, &layer);
+}
+
+/// Move the `<marker>` elements to a single `<defs>` element at the beginning
+/// of the root element, dropping the redefinitions of the same ID. This keeps
+/// the marker definitions in one place so that they can be shared.
+fn hoist_markers(svg_code: &mut String) {
+    const END_TAG: &str = "</marker>";
+
+    // (ID, element)
+    let mut markers: Vec<(&str, &str)> = Vec::new();
+    let mut rest = String::with_capacity(svg_code.len());
+    let mut remaining = &svg_code[..];
+    while let Some(start) = remaining.find("<marker") {
+        let tag_end = start + remaining[start..].find('>').unwrap();
+        let end = if remaining[..tag_end].ends_with('/') {
+            tag_end + 1 // `<marker ... />`
+        } else if let Some(i) = remaining[tag_end..].find(END_TAG) {
+            tag_end + i + END_TAG.len()
+        } else {
+            break;
+        };
+
+        let elem = &remaining[start..end];
+        let id = elem[..tag_end - start]
+            .find(" id=\"")
+            .and_then(|i| elem[i + 5..].split('"').next())
+            .unwrap_or("");
+        if !markers.iter().any(|&(other_id, _)| other_id == id) {
+            markers.push((id, elem));
+        }
+
+        rest.push_str(&remaining[..start]);
+        remaining = &remaining[end..];
+    }
+
+    if markers.is_empty() {
+        return;
+    }
+    rest.push_str(remaining);
+
+    // Remove the `<defs>` elements emptied by the above
+    let mut rest = remove_empty_defs(&rest);
+
+    let mut defs = "<defs>".to_owned();
+    for (_, elem) in markers {
+        defs += elem;
+    }
+    defs += "</defs>";
+
+    let root_end = root_start_tag(&rest).end;
+    let insert_at = root_end + rest[root_end..].find('>').unwrap() + 1;
+    rest.insert_str(insert_at, &defs);
+    *svg_code = rest;
+}
+
+/// Remove the `<defs>` elements containing nothing but whitespace.
+fn remove_empty_defs(svg_code: &str) -> String {
+    const START_TAG: &str = "<defs>";
+    const END_TAG: &str = "</defs>";
+
+    let mut out = String::with_capacity(svg_code.len());
+    let mut remaining = svg_code;
+    while let Some(start) = remaining.find(START_TAG) {
+        let content = &remaining[start + START_TAG.len()..];
+        let content_len = content.len() - content.trim_start().len();
+        let end = start + START_TAG.len() + content_len;
+        if remaining[end..].starts_with(END_TAG) {
+            out.push_str(&remaining[..start]);
+            remaining = &remaining[end + END_TAG.len()..];
+        } else {
+            out.push_str(&remaining[..end]);
+            remaining = &remaining[end..];
+        }
+    }
+    out.push_str(remaining);
+    out
+}
+
+/// Add an element to the `<defs>` element at the beginning of the root
+/// element, creating one if there isn't.
+fn add_def(svg_code: &mut String, def: &str) {
+    let root_end = root_start_tag(svg_code).end;
+    let content_start = root_end + svg_code[root_end..].find('>').unwrap() + 1;
+    if svg_code[content_start..].starts_with("<defs>") {
+        svg_code.insert_str(content_start + "<defs>".len(), def);
+    } else {
+        svg_code.insert_str(content_start, &format!("<defs>{}</defs>", def));
+    }
+}
+
+/// Percent-encode a string for use in a data URI.
+fn percent_encode(s: &str) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~!$*+,/:;=?@".contains(&b) {
+            out.push(b as char);
+        } else {
+            write!(out, "%{:02X}", b).unwrap();
+        }
+    }
+    out
+}
+
+/// Escape the characters with special meanings in the text of a Markdown
+/// link or image (`[...]`). With `literal_safe`, they are escaped by character
+/// references instead of backslashes, and so is `"`, so that the result doesn't
+/// need escaping in a string literal.
+fn escape_markdown(s: &str, literal_safe: bool) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        let special = matches!(ch, '\\' | '[' | ']' | '`' | '*' | '_' | '~' | '<' | '&');
+        if literal_safe && (special || ch == '"') {
+            write!(out, "&#{};", ch as u32).unwrap();
+        } else {
+            if special {
+                out.push('\\');
+            }
+            out.push(ch);
+        }
+    }
+    out
+}
+
+/// Escape a URL for use as a Markdown link destination. Data URIs don't
+/// contain the escaped characters, but `url-prefix` might.
+fn escape_destination(s: &str) -> String {
+    use std::fmt::Write;
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            ' ' => out.push_str("%20"),
+            '(' => out.push_str("%28"),
+            ')' => out.push_str("%29"),
+            '<' => out.push_str("%3C"),
+            '>' => out.push_str("%3E"),
+            '\\' => out.push_str("%5C"),
+            _ if ch.is_control() => {
+                let mut buf = [0; 4];
+                for b in ch.encode_utf8(&mut buf).bytes() {
+                    write!(out, "%{:02X}", b).unwrap();
+                }
+            }
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Escape a string for use in a quoted HTML or XML attribute value.
+fn escape_attr(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('\'', "&#39;")
+        .replace('"', "&#34;")
+        .replace('<', "&lt;")
+}
+
+/// Read a diagram from a file at `src`, relative to `CARGO_MANIFEST_DIR`.
+fn read_source(src: &str) -> std::result::Result<String, String> {
+    let manifest_dir =
+        std::env::var_os("CARGO_MANIFEST_DIR").ok_or("`CARGO_MANIFEST_DIR` is not set")?;
+    let path = std::path::Path::new(&manifest_dir).join(src);
+    std::fs::read_to_string(&path)
+        .map(|source| source.replace("\r\n", "\n"))
+        .map_err(|e| format!("failed to read `{}`: {}", path.display(), e))
+}
+
+/// Write an image to `out_dir` and return the URL referencing it.
+fn write_image(
+    svg_code: &str,
+    out_dir: &str,
+    url_prefix: Option<&str>,
+) -> std::result::Result<String, String> {
+    // Name the file after its content so that identical diagrams share a file
+    let file_name = format!("{:016x}.svg", content_hash(svg_code.as_bytes()));
+    let path = write_out_file(svg_code, out_dir, &file_name)?;
+
+    Ok(match url_prefix {
+        Some(url_prefix) => format!("{}{}", url_prefix, file_name),
+        // A relative URL would be resolved against the directory of each
+        // rustdoc page, which differs between nested items
+        None => file_url(&path),
+    })
+}
+
+/// Write a file named `file_name` to `out_dir` and return its path.
+fn write_out_file(
+    contents: &str,
+    out_dir: &str,
+    file_name: &str,
+) -> std::result::Result<std::path::PathBuf, String> {
+    let dir = resolve_out_dir(out_dir)?;
+    let path = dir.join(file_name);
+    std::fs::create_dir_all(&dir)
+        .and_then(|()| std::fs::write(&path, contents))
+        .map_err(|e| format!("failed to write `{}`: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Get the absolute path of `out_dir`. A relative path is relative to the
+/// target directory (`CARGO_TARGET_DIR`, or `target` in the package root) so
+/// that generated files don't end up in the source tree.
+fn resolve_out_dir(out_dir: &str) -> std::result::Result<std::path::PathBuf, String> {
+    let out_dir = std::path::Path::new(out_dir);
+    if out_dir.is_absolute() {
+        return Ok(out_dir.to_owned());
+    }
+    let target_dir = match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(target_dir) => std::path::PathBuf::from(target_dir),
+        None => {
+            let manifest_dir =
+                std::env::var_os("CARGO_MANIFEST_DIR").ok_or("`CARGO_MANIFEST_DIR` is not set")?;
+            std::path::Path::new(&manifest_dir).join("target")
+        }
+    };
+    Ok(target_dir.join(out_dir))
+}
+
+/// Get the `file:` URL of the absolute path `path`.
+fn file_url(path: &std::path::Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    if path.starts_with('/') {
+        format!("file://{}", path)
+    } else {
+        // e.g., `C:/...` on Windows
+        format!("file:///{}", path)
+    }
+}
+
+/// Calculate the 64-bit FNV-1a hash of `data`. Unlike `DefaultHasher`, the
+/// result is stable across Rust versions.
+fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+#[cfg(feature = "enable")]
+fn to_svg(art: &str, options: &DiagramOptions) -> String {
+    let node: svgbob::Node<()> = to_svg_node(art, options, &svgbob_settings(options));
+    render_node(&node)
+}
+
+#[cfg(feature = "enable")]
+fn render_node<MSG>(node: &svgbob::Node<MSG>) -> String {
+    use svgbob::Render;
+    let mut svg_code = String::new();
+    node.render(&mut svg_code).unwrap();
+
+    svg_code
+}
+
+/// Convert a diagram to a document tree with `settings`. Generic over the
+/// message type so that the tree can be embedded in a larger document with
+/// event handlers.
+#[cfg(feature = "enable")]
+fn to_svg_node<MSG>(
+    art: &str,
+    options: &DiagramOptions,
+    settings: &svgbob::Settings,
+) -> svgbob::Node<MSG> {
+    use svgbob::{
+        sauron::{html::attributes::AttributeValue, Attribute},
+        Node,
+    };
+
+    // Convert the diagram to SVG
+    let cb = svgbob::CellBuffer::from(art);
+    let (mut node, _, _) = cb.get_node_with_size(settings);
+
+    let fix_layout = options.compat >= Compat::V2;
+
+    traverse_pre_order_mut(&mut node, &mut |node| {
+        match node {
+            Node::Element(elem)
+                if elem.tag == "text" && (fix_layout || options.text_anchor.is_some()) =>
+            {
+                let mut width = 0;
+                for child in elem.get_children() {
+                    if let Some(text) = child.text() {
+                        width += xml_text_width(text, options.width_metric);
+                    }
+                }
+                let width = width as f32 * settings.scale as f32;
+
+                if fix_layout {
+                    // Fix the horizontal layouting of texts by adding a `textLength` attribute
+                    // to `<text>` elements.
+                    let text_len = match options.text_length_precision {
+                        Some(precision) => format!("{:.*}", precision, width).into(),
+                        None => width.into(),
+                    };
+                    elem.attrs.push(Attribute::new(
+                        None,
+                        "textLength",
+                        AttributeValue::from_value(text_len),
+                    ));
+                }
+
+                if let Some(anchor) = options.text_anchor {
+                    // `x` is the start of the text. Move the anchor point by
+                    // `dx` so that the text still spans the same cells.
+                    elem.attrs.push(Attribute::new(
+                        None,
+                        "text-anchor",
+                        AttributeValue::from_value(anchor.as_str().into()),
+                    ));
+                    elem.attrs.push(Attribute::new(
+                        None,
+                        "dx",
+                        AttributeValue::from_value(anchor.offset(width).into()),
+                    ));
+                }
+
+                return false;
+            }
+            _ => {}
+        }
+
+        true
+    });
+
+    // FIXME: Replace with let-else when stabilized
+    let elem = if let svgbob::Node::Element(elem) = &mut node {
+        elem
+    } else {
+        unreachable!()
+    };
+
+    // Patch the root element (`<svg>`)
+    let mut has_xmlns = false;
+    for attr in elem.attrs.iter_mut() {
+        match *attr.name() {
+            "xmlns" => has_xmlns = true,
+            "height" if fix_layout => {
+                // Fix the height of the image
+                // <https://github.com/ivanceras/svgbob/issues/77>
+                let new_height = settings.scale * 2.0 * art.lines().count() as f32;
+                *attr = Attribute::new(
+                    None,
+                    "height",
+                    AttributeValue::from_value(new_height.into()),
+                );
+            }
+            _ => {}
+        }
+    }
+    if !has_xmlns {
+        // The namespace is mandatory for a standalone SVG document, which is
+        // what a data URI is decoded into
+        elem.attrs.push(Attribute::new(
+            None,
+            "xmlns",
+            AttributeValue::from_value("http://www.w3.org/2000/svg".into()),
+        ));
+    }
+    elem.attrs.push(Attribute::new(
+        None,
+        "style",
+        AttributeValue::from_value("transform:translate(0.5px,0.5px)".into()),
+    ));
+
+    node
+}
+
+/// The svgbob settings used for rendering a diagram.
+#[cfg(feature = "enable")]
+fn svgbob_settings(options: &DiagramOptions) -> svgbob::Settings {
+    let mut settings = svgbob::Settings::default();
+    settings.stroke_width = 1.0;
+    settings.font_family = font_family(options);
+    settings.font_size = DIAGRAM_FONT_SIZE;
+    if let Some(scale) = options.scale {
+        settings.scale = scale;
+    }
+    settings
+}
+
+/// Like [`text_width`] but handles some entity references (e.g., `&amp;`).
+/// Assumes the input is in a valid form of an XML text node.
+#[cfg(feature = "enable")]
+fn xml_text_width(html_text: &str, metric: WidthMetric) -> usize {
+    html_text
+        .split('&')
+        .enumerate()
+        .map(|(i, mut part)| {
+            if i > 0 {
+                if let Some(k) = part.find(';') {
+                    // "& a m p ;"
+                    //  ^ ^^^^^ ^
+                    //  │   │   └─ This part is preserved so that this entity is
+                    //  │   │      counted as one cell
+                    //  │   └─ We remove this part now
+                    //  └─ This part is removed by `split`
+                    part = &part[k..];
+                }
+            }
+            text_width(part, metric)
+        })
+        .sum()
+}
+
+/// Measure the width of a text in character cells.
+fn text_width(text: &str, metric: WidthMetric) -> usize {
+    use unicode_width::UnicodeWidthStr;
+    match metric {
+        WidthMetric::Unicode => text.width(),
+        WidthMetric::Chars => text.chars().count(),
+        WidthMetric::Bytes => text.len(),
+    }
+}
+
+#[cfg(feature = "enable")]
+fn traverse_pre_order_mut<MSG>(
+    node: &mut svgbob::Node<MSG>,
+    cb: &mut dyn FnMut(&mut svgbob::Node<MSG>) -> bool,
+) {
+    if cb(node) {
+        if let Some(children) = node.children_mut() {
+            for child in children.iter_mut() {
+                traverse_pre_order_mut(child, cb);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "enable"))]
+fn to_svg(art: &str, options: &DiagramOptions) -> String {
+    use std::fmt::Write;
+    use unicode_width::UnicodeWidthStr;
+
+    let lines = art.lines();
+    let cols = lines
+        .clone()
+        .map(|line| line.width())
+        .fold(0, std::cmp::max);
+    let rows = lines.clone().count();
+
+    let col_width = options.scale.unwrap_or(DEFAULT_SCALE);
+    let row_height = col_width * 2.0;
+    let width = cols as f32 * col_width;
+    let height = rows as f32 * row_height;
+
+    let mut content = String::new();
+    for (i, line) in lines.enumerate() {
+        let mut x = 0.0;
+        let y = (i as f32 + 0.75) * row_height;
+        let mut last_i = 0;
+
+        // Divide `line` by whitespace so that each text span is positioned
+        // precisely at their endpoints
+        split_whitespace_indices(line, |span, start_i| {
+            x += line[last_i..start_i].width() as f32 * col_width;
+            last_i = start_i;
+
+            let width = text_width(span, options.width_metric) as f32 * col_width;
+            let text_len = match options.text_length_precision {
+                Some(precision) => format!("{:.*}", precision, width),
+                None => width.to_string(),
+            };
+            match options.text_anchor {
+                Some(anchor) => write!(
+                    content,
+                    r#"<text x="{}" y="{}" text-anchor="{}""#,
+                    x + anchor.offset(width),
+                    y,
+                    anchor.as_str(),
+                )
+                .unwrap(),
+                None => write!(content, r#"<text x="{}" y="{}""#, x, y).unwrap(),
+            }
+            if options.compat >= Compat::V2 {
+                write!(content, r#" textLength="{}""#, text_len).unwrap();
+            }
+            content.push('>');
+            escape_html(span, &mut content);
+            content.push_str("</text>");
+        });
+    }
+
+    fn split_whitespace_indices(mut s: &str, mut f: impl FnMut(&str, usize)) {
+        // Skip the the first whitespace characters
+        let s_trimmed = s.trim_start();
+        let mut offset = s.len() - s_trimmed.len();
+        s = s_trimmed;
+        while !s.is_empty() {
+            // Find the first whitespace character
+            let i = s
+                .char_indices()
+                .find(|(_, c)| c.is_whitespace())
+                .map(|(i, _)| i);
+
+            // Emit a span comprised of non-whitespace characters
+            {
+                let i = i.unwrap_or(s.len());
+                let part = &s[..i];
+                f(part, offset);
+                offset += i;
+                s = &s[i..];
+            }
+
+            // Skip the subsequent whitespace characters
+            let s_trimmed = s.trim_start();
+            offset += s.len() - s_trimmed.len();
+            s = s_trimmed;
+        }
+    }
+
+    fn escape_html(mut s: &str, out: &mut String) {
+        loop {
+            let i = s
+                .as_bytes()
+                .iter()
+                .position(|b| matches!(b, b'<' | b'>' | b'&' | 0));
+            out.push_str(&s[..i.unwrap_or(s.len())]);
+            if let Some(i) = i {
+                out.push_str(match s.as_bytes()[i] {
+                    b'<' => "&lt;",
+                    b'>' => "&gt;",
+                    b'&' => "&amp;",
+                    0 => " ",
+                    _ => unreachable!(),
+                });
+                s = &s[i + 1..];
+            } else {
+                break;
+            }
+        }
+    }
+
+    format!(
+        include_str!("minimal_template.svg"),
+        font = font_family(options),
+        font_size = DIAGRAM_FONT_SIZE,
+        width = width,
+        height = height,
+        content = content,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Process a doc comment whose lines are in separate fragments, like `///`
+    /// comments, with the default options `options` (`key=value,...`).
+    fn transform(options: &str, lines: &[&str]) -> Result<(String, Vec<String>), SvgbobdocError> {
+        let mut defaults = DiagramOptions::default();
+        defaults.set_list(options).unwrap();
+        let fragments = lines
+            .iter()
+            .map(|line| (line.to_string(), Span::call_site()))
+            .collect();
+        let (output, warnings) = transform_fragments(defaults, fragments)?;
+        Ok((output, warnings.iter().map(Error::to_string).collect()))
+    }
+
+    /// Decode the first base64-encoded data URI in `output`.
+    fn decode_image(output: &str) -> String {
+        let uri = &output[output.find("base64,").expect(output) + 7..];
+        let uri = &uri[..uri.find(|ch| ch == ')' || ch == '\'').unwrap_or(uri.len())];
+        String::from_utf8(base64::decode(uri).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn warn_tabs() {
+        let (_, warnings) = transform("", &["```svgbob", "a\t-b", "```"]).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("tab characters"), "{:?}", warnings);
+
+        let (_, warnings) = transform("", &["```svgbob", "a -b", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn xmlns() {
+        let (output, _) = transform("", &["```svgbob", "a-b", "```"]).unwrap();
+        let svg = decode_image(&output);
+        assert_eq!(
+            root_attr(&svg, "xmlns"),
+            Some("http://www.w3.org/2000/svg"),
+            "{}",
+            svg
+        );
+    }
+
+    #[test]
+    fn inline_limit() {
+        let out_dir = std::env::temp_dir().join("svgbobdoc-test-inline-limit");
+        // The limit is the length of the first image's URI
+        let (output, _) = transform("", &["```svgbob", "a-b", "```"]).unwrap();
+        let limit = output.trim_end().len() - "![]()".len();
+        let options = format!(
+            "out-dir={:?},inline-limit={}",
+            out_dir.to_str().unwrap(),
+            limit
+        );
+        let mut lines = vec!["```svgbob", "a-b", "```", "```svgbob"];
+        lines.extend(
+            std::iter::repeat("a b c d e f g h i j k l m n o p q r s t u v w x y z").take(8),
+        );
+        lines.push("```");
+        let (output, _) = transform(&options, &lines).unwrap();
+        let mut images = output.lines().filter(|line| line.starts_with("!["));
+        let small = images.next().unwrap();
+        assert!(
+            small.starts_with("![](data:image/svg+xml;base64,"),
+            "{}",
+            small
+        );
+        let large = images.next().unwrap();
+        assert!(large.starts_with("![](file://"), "{}", large);
+        let file_name = &large[large.rfind('/').unwrap() + 1..large.len() - 1];
+        assert!(out_dir.join(file_name).is_file(), "{}", file_name);
+    }
+
+    #[test]
+    fn unknown_fence_options() {
+        let (output, _) = transform("", &["```svgbob,foo,foo=bar", "a-b", "```"]).unwrap();
+        assert!(output.trim_start().starts_with("![](data:"), "{}", output);
+
+        let error = transform("", &["```svgbob,scale=1,5", "a-b", "```"]).unwrap_err();
+        assert!(error.to_string().contains("decimal numbers"), "{}", error);
+    }
+
+    #[test]
+    fn art_on_fence_line() {
+        let error = transform("", &["```svgbob +--+", "|  |", "+--+", "```"]).unwrap_err();
+        assert!(matches!(error, SvgbobdocError::InvalidFence { .. }));
+        assert!(
+            error.to_string().contains("must start on the next line"),
+            "{}",
+            error
+        );
+
+        // Not a svgbob code block
+        let (output, _) = transform("", &["```svgbobx", "```"]).unwrap();
+        assert_eq!(output, "```svgbobx\n```\n");
+    }
+
+    #[test]
+    fn literal_safe() {
+        let (output, _) = transform(
+            "literal-safe",
+            &[
+                r#"```svgbob,alt=a.b (c) \ "d"#,
+                "a-b",
+                "```",
+                r#"```svgbob,alt=e\f,border"#,
+                "a-b",
+                "```",
+            ],
+        )
+        .unwrap();
+        let literal: syn::LitStr = syn::parse_str(&format!("\"{}\"", output)).unwrap();
+        assert_eq!(literal.value(), output);
+
+        assert!(
+            output
+                .trim_start()
+                .starts_with("![a.b (c) &#92; &#34;d](data:"),
+            "{}",
+            output
+        );
+        assert!(output.contains("alt='e&#92;f'"), "{}", output);
+    }
+
+    #[test]
+    fn scale() {
+        fn sizes(options: &str, fence: &str) -> (f32, f32) {
+            let (output, _) = transform(options, &[fence, "abc", "```"]).unwrap();
+            let svg = decode_image(&output);
+            let width = root_attr(&svg, "width").unwrap().parse().unwrap();
+            let text_length = &svg[svg.find("textLength=\"").expect(&svg) + 12..];
+            let text_length = text_length[..text_length.find('"').unwrap()]
+                .parse()
+                .unwrap();
+            (width, text_length)
+        }
+
+        let (width, text_length) = sizes("", "```svgbob");
+        assert_eq!(
+            sizes("scale=16", "```svgbob"),
+            (width * 2.0, text_length * 2.0)
+        );
+        assert_eq!(
+            sizes("scale=16", "```svgbob,scale=4"),
+            (width / 2.0, text_length / 2.0)
+        );
+
+        for scale in &["0", "-8", "NaN", "inf"] {
+            let fence = format!("```svgbob,scale={},strict-render", scale);
+            let error = transform("", &[&fence, "a-b", "```"]).unwrap_err();
+            assert!(error.to_string().contains("`scale` must be"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn strip() {
+        let (output, _) = transform(
+            "strip",
+            &["Before", "", "```svgbob", "a-b", "```", "", "After"],
+        )
+        .unwrap();
+        let lines: Vec<&str> = output.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(lines, ["Before", "After"], "{:?}", output);
+
+        let (output, _) = transform("strip", &["```rust", "a-b", "```"]).unwrap();
+        assert_eq!(output, "```rust\na-b\n```\n");
+
+        // Only the diagrams with the option are removed
+        let lines = ["```svgbob,strip", "a-b", "```", "```svgbob", "a-b", "```"];
+        let (output, _) = transform("", &lines).unwrap();
+        assert_eq!(
+            output.matches("data:image/svg+xml").count(),
+            1,
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn blockquote() {
+        let lines = [
+            "> Quoted:",
+            ">",
+            "> ```svgbob",
+            "> +--+",
+            "> ```",
+            "Not quoted",
+        ];
+        let (output, _) = transform("blockquote", &lines).unwrap();
+        let (quoted, rest) = output.split_at(output.find("Not quoted").unwrap());
+        assert!(
+            quoted.lines().all(|line| line.starts_with('>')),
+            "{}",
+            output
+        );
+        assert!(quoted.contains("> ![](data:"), "{}", output);
+        assert_eq!(rest, "Not quoted\n");
+
+        // The diagram doesn't include the markers
+        let (expected, _) = transform("", &["```svgbob", "+--+", "```"]).unwrap();
+        assert_eq!(decode_image(&output), decode_image(&expected));
+
+        // Off by default
+        let (output, _) = transform("", &lines).unwrap();
+        assert_eq!(output, lines.join("\n") + "\n");
+    }
+
+    #[test]
+    fn strict_render() {
+        let (output, warnings) = transform("", &["```svgbob", "```"]).unwrap();
+        assert_eq!(warnings, ["svgbob diagram is empty"]);
+        assert!(output.trim_start().starts_with("![](data:"), "{}", output);
+
+        let error = transform("strict-render", &["```svgbob", "```"]).unwrap_err();
+        assert!(matches!(error, SvgbobdocError::Render { .. }));
+        assert_eq!(error.to_string(), "svgbob diagram is empty");
+    }
+
+    #[test]
+    fn border() {
+        let (output, _) = transform("", &["```svgbob,border", "a-b", "```"]).unwrap();
+        assert!(
+            output.trim_start().starts_with("<img src='data:"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("style='border:1px solid rgba(128,128,128,0.5);padding:4px;'"),
+            "{}",
+            output
+        );
+
+        let (output, _) = transform("", &["```svgbob", "a-b", "```"]).unwrap();
+        assert!(!output.contains("border"), "{}", output);
+    }
+
+    #[test]
+    fn indented_fence() {
+        let (output, _) =
+            transform("", &["     ```svgbob", "     +--+", "      ab", "     ```"]).unwrap();
+        assert!(output.contains("![](data:"), "{}", output);
+
+        // Unindented by the fence's indentation
+        let (expected, _) = transform("", &["```svgbob", "+--+", " ab", "```"]).unwrap();
+        assert_eq!(decode_image(&output), decode_image(&expected));
+    }
+
+    #[test]
+    fn font_weight() {
+        let (output, _) = transform("", &["```svgbob,font-weight=bold", "abc", "```"]).unwrap();
+        let svg = decode_image(&output);
+        // Inherited by the `<text>` elements
+        assert_eq!(root_attr(&svg, "font-weight"), Some("bold"), "{}", svg);
+        assert!(svg.contains("<text"), "{}", svg);
+
+        let error = transform("", &["```svgbob,font-weight=\"a;b\"", "abc", "```"]).unwrap_err();
+        assert!(error.to_string().contains("font-weight"), "{}", error);
+    }
+
+    #[test]
+    fn warn_overflowing_labels() {
+        let art = ["```svgbob", "+-----+", "| long label |", "+-----+", "```"];
+        let (_, warnings) = transform("", &art).unwrap();
+        assert_eq!(
+            warnings,
+            ["texts on line(s) 2 of the svgbob diagram overflow their enclosing boxes"]
+        );
+
+        let art = [
+            "```svgbob",
+            "+------------+",
+            "| long label |",
+            "+------------+",
+            "```",
+        ];
+        let (_, warnings) = transform("", &art).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn em_unit() {
+        let (output, _) = transform("unit=em", &["```svgbob", "abc", "```"]).unwrap();
+        let svg = decode_image(&output);
+        assert!(root_attr(&svg, "width").unwrap().ends_with("em"), "{}", svg);
+        assert!(
+            root_attr(&svg, "height").unwrap().ends_with("em"),
+            "{}",
+            svg
+        );
+        // The coordinate system stays in pixels
+        assert!(root_attr(&svg, "viewBox").is_some(), "{}", svg);
+    }
+
+    #[test]
+    fn alt_template() {
+        let lines = [
+            "```svgbob",
+            "a-b",
+            "```",
+            "```svgbob,alt=Custom",
+            "a-b",
+            "```",
+        ];
+        let (output, _) = transform(
+            "alt-template=Diagram {n}",
+            &[&lines[..], &lines[..]].concat(),
+        )
+        .unwrap();
+        let alts: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("!["))
+            .map(|line| &line[2..line.find(']').unwrap()])
+            .collect();
+        assert_eq!(alts, ["Diagram 1", "Custom", "Diagram 3", "Custom"]);
+    }
+
+    #[test]
+    fn escape_alt() {
+        let (output, _) = transform("", &["```svgbob,alt=a.b (c) [d] *e*", "a-b", "```"]).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with(r"![a.b (c) \[d\] \*e\*](data:"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn encoding() {
+        let art = ["```svgbob", "a-b", "```"];
+        let (output, _) = transform("encoding=base64", &art).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("![](data:image/svg+xml;base64,"),
+            "{}",
+            output
+        );
+        let (output, _) = transform("encoding=percent", &art).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("![](data:image/svg+xml,%3Csvg"),
+            "{}",
+            output
+        );
+        let (output, _) = transform("encoding=inline-svg", &art).unwrap();
+        assert!(output.trim_start().starts_with("<div><svg"), "{}", output);
+
+        // Overridden by the code fence header
+        let (output, _) = transform(
+            "encoding=inline-svg",
+            &["```svgbob,encoding=base64", "a-b", "```"],
+        )
+        .unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("![](data:image/svg+xml;base64,"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn scope_inline_style_sheets() {
+        let (output, _) = transform(
+            "encoding=inline-svg",
+            &["```svgbob,theme=monokai", "a-b", "```"],
+        )
+        .unwrap();
+        let id = root_attr(&output, "id").unwrap();
+        let class = root_attr(&output, "class").unwrap();
+        let sheets: String = output
+            .split("<style")
+            .skip(1)
+            .map(|sheet| &sheet[sheet.find('>').unwrap() + 1..sheet.find("</style>").unwrap()])
+            .collect();
+        for rule in sheets.split('}').filter(|rule| !rule.trim().is_empty()) {
+            let selectors = &rule[..rule.find('{').expect(rule)];
+            for selector in selectors.split(',') {
+                let selector = selector.trim();
+                assert!(
+                    selector.starts_with(&format!("#{} ", id))
+                        || selector.starts_with(&format!("#{}.{} ", id, class)),
+                    "{}",
+                    selector
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn generic_message() {
+        let options = DiagramOptions::default();
+        // A message type other than `()`
+        let node: svgbob::Node<String> =
+            to_svg_node("+--+\n|ab|\n+--+", &options, &svgbob_settings(&options));
+        assert_eq!(render_node(&node), to_svg("+--+\n|ab|\n+--+", &options));
+    }
+
+    #[test]
+    fn ascii() {
+        let (output, _) = transform("", &["```svgbob,ascii\na<b\nc-d\n```"]).unwrap();
+        assert_eq!(
+            output,
+            "<pre class='svgbob-ascii' style='background:rgba(128,128,128,0.1);'>\
+             a&lt;b&#10;c-d</pre>\n"
+        );
+    }
+
+    #[test]
+    fn text_length_precision() {
+        let art = "```svgbob,scale=7.33,text-length-precision=0\nabc\n```";
+        let (output, _) = transform("", &[art]).unwrap();
+        let svg = decode_image(&output);
+        let text_length = &svg[svg.find("textLength=\"").expect(&svg) + 12..];
+        let text_length = &text_length[..text_length.find('"').unwrap()];
+        assert_eq!(text_length, "22", "{}", svg);
+    }
+
+    /// The fixtures in `tests/snapshots` (`NAME.txt`) and the options they
+    /// are rendered with.
+    const SNAPSHOTS: &[(&str, &str)] = &[
+        ("box", ""),
+        ("arrows", "theme=monokai"),
+        ("labels", "text-anchor=middle,font-weight=bold"),
+    ];
+
+    /// Compare the SVG code of the fixtures with the snapshots
+    /// (`NAME.svg`, or `NAME.no-enable.svg` without the `enable` feature).
+    /// Missing and mismatching snapshots fail the test unless the environment
+    /// variable `SVGBOBDOC_UPDATE_SNAPSHOTS` is set, in which case they're
+    /// written.
+    #[test]
+    fn snapshots() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots");
+        let suffix = if cfg!(feature = "enable") {
+            "svg"
+        } else {
+            "no-enable.svg"
+        };
+        let update = std::env::var_os("SVGBOBDOC_UPDATE_SNAPSHOTS").is_some();
+
+        let mut mismatches = Vec::new();
+        for &(name, options) in SNAPSHOTS {
+            let art = std::fs::read_to_string(dir.join(name).with_extension("txt")).unwrap();
+            let art = art.replace("\r\n", "\n");
+            let art = art.trim_end_matches('\n');
+            let mut diagram_options = DiagramOptions::default();
+            diagram_options.set_list(options).unwrap();
+            let svg_code = render_svg(art, &diagram_options, None, &mut Vec::new());
+            // svgbob's output doesn't vary between runs, but Git might convert
+            // the line endings of the snapshots
+            let svg_code = svg_code.replace("\r\n", "\n");
+
+            let path = dir.join(format!("{}.{}", name, suffix));
+            match std::fs::read_to_string(&path) {
+                Ok(snapshot) if snapshot.replace("\r\n", "\n") == svg_code => {}
+                _ if update => std::fs::write(&path, &svg_code).unwrap(),
+                _ => mismatches.push(path.display().to_string()),
+            }
+        }
+        assert!(
+            mismatches.is_empty(),
+            "the SVG code differs from the snapshots {:?}, or they're missing; \
+             set `SVGBOBDOC_UPDATE_SNAPSHOTS` to write them",
+            mismatches
+        );
+    }
+
+    /// The IDs of the `<marker>` elements in `svg_code`, checking that they
+    /// are in the first child of the root element, a `<defs>` element.
+    fn marker_ids(svg_code: &str) -> Vec<&str> {
+        let content = &svg_code[svg_code.find('>').unwrap() + 1..];
+        let defs = &content[..content.find("</defs>").unwrap_or(0)];
+        assert!(defs.starts_with("<defs>"), "{}", svg_code);
+        assert_eq!(svg_code.matches("<defs").count(), 1, "{}", svg_code);
+        assert_eq!(
+            defs.matches("<marker").count(),
+            svg_code.matches("<marker").count(),
+            "{}",
+            svg_code
+        );
+        defs.split("<marker id=\"")
+            .skip(1)
+            .map(|elem| elem.split('"').next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hoist_markers() {
+        let mut svg_code =
+            "<svg width=\"8\"><defs>\n  <marker id=\"arrow\"><path/></marker>\n</defs>\
+                            <g><defs><marker id=\"arrow\"><path/></marker>\
+                            <marker id=\"circle\"/></defs></g></svg>"
+                .to_owned();
+        super::hoist_markers(&mut svg_code);
+        assert_eq!(marker_ids(&svg_code), ["arrow", "circle"]);
+        assert!(svg_code.ends_with("<g></g></svg>"), "{}", svg_code);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn hoist_markers_of_svgbob() {
+        let svg_code = render_svg(
+            "a --> b\nc <-- d",
+            &DiagramOptions::default(),
+            None,
+            &mut Vec::new(),
+        );
+        let ids = marker_ids(&svg_code);
+        assert!(!ids.is_empty(), "{}", svg_code);
+        for id in ids.iter() {
+            assert_eq!(ids.iter().filter(|other| *other == id).count(), 1, "{}", id);
+        }
+    }
+
+    #[test]
+    fn debug_grid() {
+        const GRID: &str = "<path d=\"M0 0V16M8 0V16M16 0V16M0 0H16M0 16H16\"";
+        let (output, _) = transform("", &["```svgbob,debug-grid", "ab", "```"]).unwrap();
+        assert!(decode_image(&output).contains(GRID), "{}", output);
+        let (output, _) = transform("", &["```svgbob", "ab", "```"]).unwrap();
+        assert!(!decode_image(&output).contains(GRID), "{}", output);
+    }
+
+    #[test]
+    fn reference_links() {
+        let lines = [
+            "```svgbob",
+            "x",
+            "```",
+            "a",
+            "```svgbob,alt=B",
+            "y",
+            "```",
+            "```svgbob",
+            "x",
+            "```",
+        ];
+        let (output, _) = transform("reference-links", &lines).unwrap();
+        let labels: Vec<&str> = output
+            .match_indices("][svgbob-diagram-")
+            .map(|(i, _)| {
+                let rest = &output[i + 2..];
+                &rest[..rest.find(']').unwrap()]
+            })
+            .collect();
+        assert_eq!(labels.len(), 3, "{}", output);
+        assert_ne!(labels[0], labels[1]);
+        assert_eq!(labels[0], labels[2]);
+        for label in &labels[..2] {
+            let definition = format!("\n[{}]: data:image/svg+xml;base64,", label);
+            assert_eq!(output.matches(&*definition).count(), 1, "{}", output);
+        }
+        assert!(output.contains("![B][svgbob-diagram-"), "{}", output);
+        assert!(!output.contains("]("), "{}", output);
+    }
+
+    #[test]
+    fn block_doc_comment() {
+        let comment = "\n * Text\n *\n * ```svgbob\n *  x-->\n * ```\n * ```\n * code\n * ```\n ";
+        let (output, _) = transform("", &[comment]).unwrap();
+        assert!(
+            output.starts_with("\n * Text\n *\n *\n *\n * ![](data:"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains(")\n * ```\n * code\n * ```\n"),
+            "{}",
+            output
+        );
+        // The asterisks aren't part of the art
+        assert!(decode_image(&output).contains(">x"), "{}", output);
+    }
+
+    #[test]
+    fn object() {
+        let (output, _) = transform("", &["```svgbob,object,alt=A&B", "x", "```"]).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("<object type='image/svg+xml' data='data:image/svg+xml;base64,"),
+            "{}",
+            output
+        );
+        // The alternative text is the fallback content
+        assert!(output.contains("'>A&amp;B</object>"), "{}", output);
+        let (_, warnings) =
+            transform("", &["```svgbob,object,encoding=inline-svg", "x", "```"]).unwrap();
+        assert!(warnings[0].contains("can't be used"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn front_matter() {
+        let lines = [
+            "```svgbob",
+            "---",
+            "alt: \"A, b\"",
+            "border:",
+            "scale: 10",
+            "---",
+            "xy",
+            "```",
+        ];
+        let (output, _) = transform("", &lines).unwrap();
+        assert!(output.contains("alt='A, b' style='border"), "{}", output);
+        let image = decode_image(&output);
+        assert!(image.contains(" width=\"20\""), "{}", image);
+        assert!(!image.contains("---"), "{}", image);
+
+        // Art that looks like a front matter
+        for art in ["---\nNote: see below\n---", "---\nxy\n---", "---\n---"].iter() {
+            let block = format!("```svgbob,ascii\n{}\n```", art);
+            let (output, warnings) = transform("", &[&block]).unwrap();
+            assert!(warnings.is_empty(), "{:?}", warnings);
+            assert!(output.contains(&art.replace('\n', "&#10;")), "{}", output);
+        }
+
+        // Invalid values of known options are still errors
+        assert!(transform("", &["```svgbob", "---", "scale: x", "---", "```"]).is_err());
+    }
+
+    #[test]
+    fn warn_merged_diagrams() {
+        let (_, warnings) = transform("", &["```svgbob", "a", "```svgbob", "b", "```"]).unwrap();
+        assert!(
+            warnings
+                .iter()
+                .any(|w| w.contains("line 2 of the svgbob diagram looks like the opening fence")),
+            "{:?}",
+            warnings
+        );
+        let (_, warnings) =
+            transform("", &["```svgbob", "a", "```", "```svgbob", "b", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn trim_blank_lines() {
+        fn height(options: &str) -> f32 {
+            let (output, _) = transform(options, &["```svgbob\nab \n\n  \n```"]).unwrap();
+            let image = decode_image(&output);
+            root_attr(&image, "height").unwrap().parse().unwrap()
+        }
+        let (trimmed, untrimmed) = (height("trim-blank-lines"), height(""));
+        assert!(trimmed < untrimmed, "{} {}", trimmed, untrimmed);
+    }
+
+    #[test]
+    fn a11y() {
+        let (output, _) = transform("", &["```svgbob,a11y=\"<A> & 'B'\"", "ab", "```"]).unwrap();
+        let image = decode_image(&output);
+        assert!(
+            image.contains(" role=\"img\" aria-label=\"&lt;A> &amp; &#39;B&#39;\""),
+            "{}",
+            image
+        );
+        let (output, _) = transform("", &["```svgbob", "ab", "```"]).unwrap();
+        assert!(!decode_image(&output).contains("aria-label"), "{}", output);
+    }
+
+    #[test]
+    fn lang_hint() {
+        let (output, warnings) =
+            transform("", &["```svgbob,lang:rust,border", "ab", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        // The other options still apply
+        assert!(
+            output.trim_start().starts_with("<img src='data:"),
+            "{}",
+            output
+        );
+        assert!(output.contains("style='border"), "{}", output);
+    }
+
+    #[test]
+    fn adversarial_values() {
+        assert_eq!(
+            escape_destination("a b(c)<d>\\e\n"),
+            "a%20b%28c%29%3Cd%3E%5Ce%0A"
+        );
+        let out_dir = std::env::temp_dir().join("svgbob-test-adversarial-values");
+        let options = format!(
+            "out-dir={:?},url-prefix=\"https://example.com/a (b)/\",inline-limit=0",
+            out_dir.to_str().unwrap()
+        );
+        let (output, _) =
+            transform(&options, &["```svgbob,alt=\"](x) <b>\"", "ab", "```"]).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("![\\](x) \\<b>](https://example.com/a%20%28b%29/"),
+            "{}",
+            output
+        );
+
+        let (output, _) =
+            transform("", &["```svgbob,alt=\"'><script>\",border", "ab", "```"]).unwrap();
+        assert!(output.contains(" alt='&#39;>&lt;script>'"), "{}", output);
+
+        assert!(transform("", &["```svgbob,font-weight=\"bold\\\"/>\"", "ab", "```"]).is_err());
+    }
+
+    #[test]
+    fn symbols() {
+        let lines = [
+            "```svgbob,id=d1",
+            "ab",
+            "```",
+            "text",
+            "```svgbob,ref=d1",
+            "```",
+        ];
+        let (output, warnings) = transform("encoding=inline-svg", &lines).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(
+            output.matches("<symbol id=\"d1\" ").count(),
+            1,
+            "{}",
+            output
+        );
+        // The definition is also referenced to display it
+        assert_eq!(
+            output.matches("<use href=\"#d1\"/>").count(),
+            2,
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("<use href=\"#d1\"/></svg></div>\n\ntext\n\n<div><svg"),
+            "{}",
+            output
+        );
+
+        let lines = ["```svgbob,ref=d1", "```"];
+        assert!(transform("encoding=inline-svg,strict-render", &lines).is_err());
+        // `<use>` can't reference elements in other images
+        assert!(transform("strict-render", &["```svgbob,id=d1", "x", "```"]).is_err());
+    }
+
+    #[test]
+    fn normalize_line_endings() {
+        let comment = "a\r\nb\rc\n```svgbob\r\nx\r\n```\r\nd";
+        let (output, _) = transform("normalize-line-endings", &[comment]).unwrap();
+        assert!(!output.contains('\r'), "{:?}", output);
+        assert!(output.starts_with("a\nb\nc\n![](data:"), "{:?}", output);
+        assert!(output.ends_with(")\nd\n"), "{:?}", output);
+    }
+
+    #[test]
+    fn aspect() {
+        let size = |fence| -> (f32, f32) {
+            let (output, _) = transform("", &[fence, "ab", "```"]).unwrap();
+            let image = decode_image(&output);
+            let attr = |name| -> f32 { root_attr(&image, name).unwrap().parse().unwrap() };
+            (attr("width"), attr("height"))
+        };
+        let (width, height) = size("```svgbob");
+        assert!(width < height * 2.0);
+        assert_eq!(size("```svgbob,aspect=2:1"), (height * 2.0, height));
+
+        // The diagram is centered
+        let (output, _) = transform("", &["```svgbob,aspect=2:1", "ab", "```"]).unwrap();
+        let image = decode_image(&output);
+        let view_box = format!(
+            "viewBox=\"{} 0 {} {}\"",
+            (width - height * 2.0) / 2.0,
+            height * 2.0,
+            height
+        );
+        assert!(image.contains(&view_box), "{}", image);
+        assert!(
+            image.contains(" preserveAspectRatio=\"xMidYMid meet\""),
+            "{}",
+            image
+        );
+
+        assert!(transform("", &["```svgbob,aspect=0:1", "ab", "```"]).is_err());
+    }
+
+    #[test]
+    fn src() {
+        let (output, warnings) =
+            transform("", &["```svgbob,src=tests/fixtures/two-lines.bob", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let (expected, _) = transform("", &["```svgbob", "ab", "cd", "```"]).unwrap();
+        assert_eq!(decode_image(&output), decode_image(&expected));
+
+        let lines = ["```svgbob,src=tests/fixtures/missing.bob", "```"];
+        let e = transform("strict-render", &lines).unwrap_err();
+        assert!(e.to_string().contains("failed to read"), "{}", e);
+    }
+
+    #[test]
+    fn selectable() {
+        let lines = ["```svgbob,selectable", "a<b", "", "  c", "```"];
+        let (output, _) = transform("encoding=inline-svg", &lines).unwrap();
+        assert!(
+            output.contains(
+                "<g fill-opacity=\"0\" xml:space=\"preserve\">\
+                 <text x=\"0\" y=\"12\" textLength=\"24\">a&lt;b</text>\
+                 <text x=\"0\" y=\"44\" textLength=\"24\">  c</text></g></svg>"
+            ),
+            "{}",
+            output
+        );
+        let (output, _) = transform("encoding=inline-svg", &["```svgbob", "ab", "```"]).unwrap();
+        assert!(!output.contains("fill-opacity=\"0\""), "{}", output);
+    }
+
+    #[test]
+    fn stroke_linecap_and_linejoin() {
+        let lines = ["```svgbob,stroke-linejoin=round", "ab", "```"];
+        let (output, _) = transform("stroke-linecap=square", &lines).unwrap();
+        let image = decode_image(&output);
+        assert!(
+            image.contains("{stroke-linecap:square;stroke-linejoin:round;}</style></svg>"),
+            "{}",
+            image
+        );
+    }
+
+    #[test]
+    fn fail_softly() {
+        let lines = [
+            "```svgbob,src=tests/fixtures/missing.bob",
+            "```",
+            "```svgbob",
+            "ok",
+            "```",
+        ];
+        let (output, warnings) = transform("", &lines).unwrap();
+        assert!(
+            output.trim_start().starts_with(
+                "<pre class='svgbob-error' style='color:#c00'>svgbob error: failed to read `"
+            ),
+            "{}",
+            output
+        );
+        // The other diagram is still converted
+        assert!(output.contains("![](data:"), "{}", output);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("failed to read"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn size_properties() {
+        let (output, _) = transform("", &["```svgbob,size-properties", "abc", "```"]).unwrap();
+        let image = decode_image(&output);
+        let properties = format!(
+            "--dw:{}px;--dh:{}px\"",
+            root_attr(&image, "width").unwrap(),
+            root_attr(&image, "height").unwrap()
+        );
+        assert!(image.contains(&properties), "{}", image);
+    }
+
+    #[test]
+    fn fences_after_bom() {
+        let (output, _) = transform("", &["\u{feff}```svgbob", "ab", "\u{200b}```", "x"]).unwrap();
+        assert!(output.trim_start().starts_with("![](data:"), "{:?}", output);
+        assert!(output.ends_with(")\nx\n"), "{:?}", output);
+    }
+
+    #[test]
+    fn compat() {
+        let image = |compat| {
+            let (output, _) = transform(compat, &["```svgbob", "ab", "```"]).unwrap();
+            decode_image(&output)
+        };
+        assert!(!image("compat=1").contains("textLength"));
+        assert!(image("compat=2").contains("textLength"));
+        assert_eq!(image("compat=2"), image(""));
+        assert!(transform("", &["```svgbob,compat=3", "ab", "```"]).is_err());
+    }
+
+    #[test]
+    fn margin() {
+        let (output, _) = transform("", &["```svgbob,margin=\"1em 0\"", "ab", "```"]).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("<div style='margin:1em 0;'><img src='data:"),
+            "{}",
+            output
+        );
+        assert!(output.ends_with(" alt=''></div>\n"), "{}", output);
+    }
+
+    #[test]
+    fn image_rendering() {
+        let lines = ["```svgbob,image-rendering=pixelated", "ab", "```"];
+        let (output, _) = transform("", &lines).unwrap();
+        assert!(
+            output.contains(" style='image-rendering:pixelated;'"),
+            "{}",
+            output
+        );
+        let (output, _) = transform("", &["```svgbob", "ab", "```"]).unwrap();
+        assert!(!output.contains("image-rendering"), "{}", output);
+    }
+
+    #[test]
+    fn directive_comment() {
+        let lines = [
+            "<!-- svgbob: image-rendering=pixelated, alt=\"x y\" -->",
+            "```svgbob,border",
+            "ab",
+            "```",
+        ];
+        let (output, warnings) = transform("", &lines).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(output.starts_with("<!-- svgbob"), "{}", output);
+        assert!(output.contains(" alt='x y' style='border:"), "{}", output);
+        assert!(output.contains("image-rendering:pixelated;"), "{}", output);
+
+        // Not immediately preceding a fence
+        let lines = ["<!-- svgbob: border -->", "", "```svgbob", "ab", "```"];
+        let (output, warnings) = transform("", &lines).unwrap();
+        assert!(output.contains("![]("), "{}", output);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+
+        let lines = ["<!-- svgbob: foo -->", "```svgbob", "ab", "```"];
+        assert!(transform("", &lines).is_err());
+    }
+
+    #[test]
+    fn dry_run() {
+        let (output, warnings) = transform("dry-run", &["```svgbob", "ab", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(!output.contains("base64"), "{}", output);
+        assert!(
+            output.trim_start().starts_with("<pre class='svgbob-ascii'"),
+            "{}",
+            output
+        );
+
+        // The label is defined so that the references to it still resolve
+        let lines = ["```svgbob,[a]", "ab", "```", "![A][a]"];
+        let (output, warnings) = transform("dry-run", &lines).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(
+            output.trim(),
+            format!("[a]: {}\n\n![A][a]", DRY_RUN_PLACEHOLDER),
+            "{}",
+            output
+        );
+
+        // The diagrams are still checked
+        let lines = ["```svgbob,ref=x", "```"];
+        assert!(transform("dry-run,strict-render,encoding=inline-svg", &lines).is_err());
+    }
+
+    #[test]
+    fn text_anchor() {
+        let lines = ["```svgbob,text-anchor=end", " ab", "```"];
+        let (output, _) = transform("encoding=inline-svg", &lines).unwrap();
+        assert!(output.contains(" text-anchor=\"end\""), "{}", output);
+        // The anchor is moved to the end of the text
+        #[cfg(feature = "enable")]
+        assert!(output.contains(" dx=\"16\""), "{}", output);
+        #[cfg(not(feature = "enable"))]
+        assert!(output.contains("<text x=\"24\""), "{}", output);
+    }
+
+    #[test]
+    fn auto_encoding() {
+        let output = |encoding| {
+            let fence = format!("```svgbob,encoding={}", encoding);
+            transform("", &[&fence, "a<b", "```"]).unwrap().0
+        };
+        let (percent, base64) = (output("percent"), output("base64"));
+        assert_ne!(percent.len(), base64.len());
+        let shorter = if percent.len() < base64.len() {
+            percent
+        } else {
+            base64
+        };
+        assert_eq!(output("auto"), shorter);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace() {
+        trace::take_records();
+        let lines = [
+            "```svgbob,group=a",
+            "ab",
+            "```",
+            "```svgbob,group=a",
+            "abc",
+            "```",
+        ];
+        transform("", &lines).unwrap();
+        let records = trace::take_records();
+        // The records of the measurement pass are suppressed
+        let opened = records
+            .iter()
+            .filter(|record| record.starts_with("opened a svgbob code block: "))
+            .count();
+        assert_eq!(opened, 2, "{:#?}", records);
+        let closed: Vec<_> = records
+            .iter()
+            .filter(|record| record.starts_with("closed a svgbob code block with "))
+            .collect();
+        assert_eq!(
+            closed, ["closed a svgbob code block with 1 line(s)"; 2],
+            "{:#?}",
+            records
+        );
+    }
+
+    #[test]
+    fn alt_prefix() {
+        let lines = [
+            "```svgbob,alt=A",
+            "ab",
+            "```",
+            "```svgbob",
+            "ab",
+            "```",
+            "```svgbob,alt=",
+            "ab",
+            "```",
+        ];
+        let (output, _) =
+            transform("alt-prefix=\"Diagram: \",alt-template=\"#{n}\"", &lines).unwrap();
+        let alts: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("!["))
+            .map(|line| &line[2..line.find(']').unwrap()])
+            .collect();
+        // Decorative images stay decorative
+        assert_eq!(alts, ["Diagram: A", "Diagram: #2", ""]);
+    }
+
+    #[test]
+    fn fence_at_fragment_end() {
+        // The code block is continued by the next fragment
+        for lines in [
+            &["x\n```svgbob", "ab", "```"][..],
+            &["x\n```svgbob\n", "ab\n```\ny"],
+        ]
+        .iter()
+        {
+            let (output, _) = transform("", lines).unwrap();
+            assert!(output.contains("![](data:"), "{}", output);
+        }
+
+        // The code block is left open by the last fragment
+        for lines in [
+            &["x\n```svgbob"][..],
+            &["```svgbob\nab"],
+            &["```svgbob", "ab"],
+        ]
+        .iter()
+        {
+            match transform("", lines) {
+                Err(SvgbobdocError::UnclosedCodeBlock { .. }) => {}
+                result => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn link_svg() {
+        let out_dir = std::env::temp_dir().join("svgbobdoc-test-link-svg");
+        let options = format!("out-dir={:?}", out_dir.to_str().unwrap());
+        let (output, _) = transform(&options, &["```svgbob,link-svg", "a-b", "```"]).unwrap();
+        assert!(
+            output.trim_start().starts_with("[diagram](file://"),
+            "{}",
+            output
+        );
+        assert!(output.ends_with(".svg)\n"), "{}", output);
+        let file_name = &output[output.rfind('/').unwrap() + 1..output.len() - 2];
+        assert!(out_dir.join(file_name).is_file(), "{}", file_name);
+
+        // A link needs a file
+        assert!(transform("strict-render", &["```svgbob,link-svg", "a-b", "```"]).is_err());
+    }
+
+    #[test]
+    fn width_metric() {
+        for &(metric, width) in [("unicode", 32), ("chars", 16), ("bytes", 48)].iter() {
+            let fence = format!("```svgbob,width-metric={}", metric);
+            let (output, _) = transform("encoding=inline-svg", &[&fence, "日本", "```"]).unwrap();
+            assert!(
+                output.contains(&format!(" textLength=\"{}\"", width)),
+                "{}: {}",
+                metric,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn scroll() {
+        let (output, _) = transform("", &["```svgbob,scroll,margin=1em", "ab", "```"]).unwrap();
+        assert!(
+            output
+                .trim_start()
+                .starts_with("<div style='margin:1em;overflow-x:auto;'><img "),
+            "{}",
+            output
+        );
+        // The image isn't scaled down to fit
+        assert!(
+            output.contains(" style='max-width:none;'></div>"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn substitutions() {
+        let lines = ["```svgbob,subst:a=x,subst:x=a", "ax", "```"];
+        let (output, _) = transform("encoding=inline-svg", &lines).unwrap();
+        // The substitutions are applied at the same time
+        assert!(output.contains(">xa<"), "{}", output);
+        assert!(transform("", &["```svgbob,subst:ab", "ax", "```"]).is_err());
+    }
+
+    #[test]
+    fn content_ids() {
+        let id = |art| {
+            let (output, _) = transform("encoding=inline-svg", &["```svgbob", art, "```"]).unwrap();
+            let i = output.find(" id=\"svgbob-").expect(&output);
+            output[i..i + 29].to_owned()
+        };
+        assert_eq!(id("ab"), id("ab"));
+        assert_ne!(id("ab"), id("ac"));
+    }
+
+    #[test]
+    fn dedent() {
+        // The lines would be an indented code block
+        let lines = ["\t\tx", "\t\t```svgbob", "\t\tab", "", "\t\t```"];
+        let (output, _) = transform("", &lines).unwrap();
+        assert!(!output.contains("data:"), "{}", output);
+        let (output, _) = transform("dedent", &lines).unwrap();
+        assert!(output.starts_with("x\n"), "{}", output);
+        assert!(output.contains("\n![](data:"), "{}", output);
+    }
+
+    #[test]
+    fn svgbob_fences() {
+        assert!(is_svgbob_fence("svgbob"));
+        assert!(is_svgbob_fence("svgbob,"));
+        assert!(is_svgbob_fence("svgbob,scale=2"));
+        assert!(!is_svgbob_fence("svgbobx"));
+        assert!(!is_svgbob_fence("svgbob scale=2"));
+        assert!(!is_svgbob_fence("rust"));
+        assert!(!is_svgbob_fence(""));
+
+        // Not reported as nested opening fences
+        let (_, warnings) = transform("", &["```svgbob", "ab", "```svgbobx", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn warn_width() {
+        let art = "-".repeat(40);
+        let (output, _) = transform("", &["```svgbob", &art, "```"]).unwrap();
+        let image = decode_image(&output);
+        let width: usize = root_attr(&image, "width").unwrap().parse().unwrap();
+
+        let warnings = |options: &str| {
+            let options = format!("warn-width={},{}", width - 1, options);
+            transform(&options, &["```svgbob", &art, "```"]).unwrap().1
+        };
+        let w = warnings("");
+        assert_eq!(w.len(), 1, "{:?}", w);
+        assert!(w[0].contains(&format!("is {}px wide", width)), "{:?}", w);
+        assert!(warnings("scroll").is_empty());
+        assert!(warnings("warn-width=0").is_empty());
+        assert!(warnings(&format!("warn-width={}", width)).is_empty());
+        // The width of the image is checked, including the padding of `card`
+        assert_eq!(
+            warnings(&format!("warn-width={},card", width + 15)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn font_primary() {
+        let mut options = DiagramOptions::default();
+        options.set_part("font-primary=\"My Mono\"").unwrap();
+        assert_eq!(font_family(&options), format!("'My Mono',{}", DIAGRAM_FONT));
+
+        let fence = "```svgbob,font-primary=\"My Mono\"";
+        let (output, _) = transform("encoding=inline-svg", &[fence, "ab", "```"]).unwrap();
+        assert!(output.contains("'My Mono','Source Code Pro'"), "{}", output);
+
+        // The name would end the quoted string
+        assert!(transform("", &["```svgbob,font-primary=\"a'b\"", "ab", "```"]).is_err());
+    }
+
+    #[test]
+    fn card() {
+        let image = |fence| {
+            let (output, warnings) = transform("", &[fence, "ab", "```"]).unwrap();
+            assert!(warnings.is_empty(), "{:?}", warnings);
+            decode_image(&output)
+        };
+        let attr = |image: &str, name| -> f32 { root_attr(image, name).unwrap().parse().unwrap() };
+        let plain = image("```svgbob");
+        let (width, height) = (attr(&plain, "width"), attr(&plain, "height"));
+
+        let image = image("```svgbob,card");
+        assert_eq!(
+            (attr(&image, "width"), attr(&image, "height")),
+            (width + CARD_PADDING * 2.0, height + CARD_PADDING * 2.0)
+        );
+        let view_box = format!(
+            "viewBox=\"-8 -8 {} {}\"",
+            width + CARD_PADDING * 2.0,
+            height + CARD_PADDING * 2.0
+        );
+        assert!(image.contains(&view_box), "{}", image);
+        let rect = format!(
+            "<rect class=\"svgbob-card\" x=\"-7.5\" y=\"-7.5\" width=\"{}\" height=\"{}\" rx=\"6\"",
+            width + 15.0,
+            height + 15.0
+        );
+        let card = image.find(&rect).expect(&image);
+        // Drawn over the backdrop
+        if let Some(backdrop) = find_backdrop(&image) {
+            assert_eq!(backdrop.end, card);
+        }
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn card_without_backdrop() {
+        let mut options = DiagramOptions::default();
+        options.set_part("card").unwrap();
+        let mut warnings = Vec::new();
+        let svg_code = "<svg width=\"16\" height=\"16\"><text>ab</text></svg>".to_owned();
+        let svg_code = apply_svg_options(svg_code, "ab", &options, None, &mut warnings);
+        assert!(
+            svg_code.contains("\"><rect class=\"svgbob-card\""),
+            "{}",
+            svg_code
+        );
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    }
+
+    #[test]
+    fn backdrop() {
+        let svg_code = "<svg><rect x=\"0\"/><rect x=\"0\" class=\"backdrop\" y=\"0\"></rect></svg>";
+        assert_eq!(
+            &svg_code[find_backdrop(svg_code).unwrap()],
+            "<rect x=\"0\" class=\"backdrop\" y=\"0\"></rect>"
+        );
+        let svg_code = "<svg><rect class='backdrop'/></svg>";
+        assert_eq!(
+            &svg_code[find_backdrop(svg_code).unwrap()],
+            "<rect class='backdrop'/>"
+        );
+        assert_eq!(
+            find_backdrop("<svg><rect class=\"backdrops\"/></svg>"),
+            None
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn backdrop_of_svgbob() {
+        let svg_code = render_svg("ab", &DiagramOptions::default(), None, &mut Vec::new());
+        assert!(find_backdrop(&svg_code).is_some(), "{}", svg_code);
+    }
+
+    #[test]
+    fn closing_fence_alone() {
+        let mut state = TextProcState::new(DiagramOptions::default());
+        for line in ["text", "", "```svgbob", "ab"].iter() {
+            state.step(line, Span::call_site()).unwrap();
+        }
+        match state.step("```", Span::call_site()).unwrap() {
+            TextProcOutput::Fragment(fragment) => {
+                assert!(fragment.starts_with("![](data:"), "{:?}", fragment);
+                assert!(fragment.ends_with(')'), "{:?}", fragment);
+            }
+            output => panic!("{:?}", output),
+        }
+
+        // The removed lines still leave line breaks, so the image doesn't
+        // continue the preceding paragraph
+        let (output, _) = transform("", &["Diagram:", "```svgbob", "ab", "```", "After"]).unwrap();
+        assert!(
+            output.starts_with("Diagram:\n\n\n![](data:"),
+            "{:?}",
+            output
+        );
+        assert!(output.ends_with(")\nAfter\n"), "{:?}", output);
+    }
+
+    #[test]
+    fn line_break_contract() {
+        let mut state = TextProcState::new(DiagramOptions::default());
+        let mut step = |fragment| state.step(fragment, Span::call_site()).unwrap();
+        assert!(matches!(step("prose"), TextProcOutput::Passthrough));
+        match step("x\n```svgbob") {
+            // The line break preceding a removed last line is removed
+            TextProcOutput::Fragment(fragment) => assert_eq!(fragment, "x"),
+            output => panic!("{:?}", output),
+        }
+        assert!(matches!(step("ab"), TextProcOutput::Empty));
+        match step("cd\n```\ny") {
+            TextProcOutput::Fragment(fragment) => {
+                assert!(fragment.starts_with("![](data:"), "{:?}", fragment);
+                assert!(fragment.ends_with(")\ny"), "{:?}", fragment);
+            }
+            output => panic!("{:?}", output),
+        }
+        match step("```svgbob\nab\n```\n") {
+            // Like the input fragment
+            TextProcOutput::Fragment(fragment) => {
+                assert!(fragment.ends_with(")\n"), "{:?}", fragment)
+            }
+            output => panic!("{:?}", output),
+        }
+        match step("```svgbob,encoding=inline-svg\nab\n```") {
+            TextProcOutput::Fragment(fragment) => {
+                assert!(fragment.ends_with("</svg></div>\n"), "{:?}", fragment)
+            }
+            output => panic!("{:?}", output),
+        }
+    }
+
+    #[test]
+    fn diagram_only_doc_comment() {
+        let line_comments: &[&str] = &[" ```svgbob", " a-b", " ```"];
+        let block_comment: &[&str] = &["\n```svgbob\na-b\n```\n"];
+        for lines in &[line_comments, block_comment] {
+            let (output, warnings) = transform("", lines).unwrap();
+            assert!(warnings.is_empty(), "{:?}", warnings);
+            // Nothing but the image and line breaks
+            let image = output.trim();
+            assert!(
+                image.starts_with("![](data:image/svg+xml;base64,"),
+                "{:?}",
+                output
+            );
+            assert!(image.ends_with(')'), "{:?}", output);
+            assert_eq!(image.matches("![](").count(), 1, "{:?}", output);
+            assert!(!image.contains(char::is_whitespace), "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        let (output, _) = transform(
+            "",
+            &["```svgbob,encoding=inline-svg,fallback,alt=A", "a-b", "```"],
+        )
+        .unwrap();
+        let output = output.trim();
+        assert!(
+            output.starts_with("<div role='img' aria-label='A'><svg"),
+            "{}",
+            output
+        );
+        let id = root_attr(output, "id").unwrap();
+        assert!(
+            output.contains(&format!(
+                "</svg></div><noscript><style>#{}{{display:none}}</style>\
+                 <img src='data:image/svg+xml;base64,",
+                id
+            )),
+            "{}",
+            output
+        );
+        assert!(output.ends_with("' alt='A'></noscript>"), "{}", output);
+        // The image is the inline SVG code, whose line breaks were replaced
+        let inline = &output[output.find("<svg").unwrap()..output.find("</div>").unwrap()];
+        assert_eq!(decode_image(output).replace('\n', " "), inline);
+
+        for fence in &[
+            "```svgbob,fallback",
+            "```svgbob,encoding=inline-svg,fallback,ref=x",
+        ] {
+            assert!(transform("strict-render", &[fence, "a-b", "```"]).is_err());
+        }
+    }
+
+    #[test]
+    fn theme() {
+        let (output, _) = transform("", &["```svgbob,theme=monokai", "a-b", "```"]).unwrap();
+        let svg_code = decode_image(&output);
+        for rule in &[
+            " polyline{stroke:#66d9ef;}",
+            " .filled{fill:#66d9ef;}",
+            " text{fill:#f8f8f2;}",
+            "{stroke:none;fill:#272822;}",
+        ] {
+            assert!(svg_code.contains(rule), "{}: {}", rule, svg_code);
+        }
+
+        let (output, _) = transform("", &["```svgbob,theme=solarized", "a-b", "```"]).unwrap();
+        let svg_code = decode_image(&output);
+        for rule in &[
+            " polyline{stroke:#586e75;}",
+            " text{fill:#657b83;}",
+            "{stroke:none;fill:#fdf6e3;}",
+        ] {
+            assert!(svg_code.contains(rule), "{}: {}", rule, svg_code);
+        }
+
+        let presets = r#"theme-presets="dusk:#8be9fd,#f8f8f2,#282a36 ink:black,black,white""#;
+        let (output, _) = transform(presets, &["```svgbob,theme=dusk", "a-b", "```"]).unwrap();
+        let svg_code = decode_image(&output);
+        for rule in &[
+            " polyline{stroke:#8be9fd;}",
+            " text{fill:#f8f8f2;}",
+            "{stroke:none;fill:#282a36;}",
+        ] {
+            assert!(svg_code.contains(rule), "{}: {}", rule, svg_code);
+        }
+
+        let error =
+            transform("strict-render", &["```svgbob,theme=dusk", "a-b", "```"]).unwrap_err();
+        assert!(
+            error.to_string().contains("unknown theme `dusk`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn keep_trailing_newline() {
+        let lines = ["```svgbob", "a-b", "", "```"];
+        let height = |options| {
+            let (output, _) = transform(options, &lines).unwrap();
+            let svg_code = decode_image(&output);
+            root_attr(&svg_code, "height")
+                .unwrap()
+                .parse::<f32>()
+                .unwrap()
+        };
+        // The blank line is an empty row only if the line break is kept
+        assert!(height("keep-trailing-newline") > height(""));
+        let (output, _) = transform("", &lines).unwrap();
+        let (trimmed, _) = transform("", &["```svgbob", "a-b", "```"]).unwrap();
+        assert_eq!(output.trim(), trimmed.trim());
+    }
+
+    #[test]
+    fn fill_gradient() {
+        let (output, warnings) =
+            transform("", &["```svgbob,fill-gradient=#aaa,#333", "a->b", "```"]).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        let svg_code = decode_image(&output);
+        let start = svg_code.find("<linearGradient id=\"").expect(&svg_code) + 20;
+        let id = &svg_code[start..start + svg_code[start..].find('"').unwrap()];
+        assert!(id.starts_with("svgbob-gradient-"), "{}", id);
+        assert!(
+            svg_code.contains(
+                "<stop offset=\"0\" stop-color=\"#aaa\"/><stop offset=\"1\" stop-color=\"#333\"/>"
+            ),
+            "{}",
+            svg_code
+        );
+        assert!(
+            svg_code.contains(&format!(".filled{{fill:url(#{});}}", id)),
+            "{}",
+            svg_code
+        );
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn fill_gradient_without_filled_shapes() {
+        let (_, warnings) =
+            transform("", &["```svgbob,fill-gradient=\"#aaa,#333\"", "a", "```"]).unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].contains("without filled shapes"),
+            "{:?}",
+            warnings
+        );
+    }
+
+    #[test]
+    fn has_classes() {
+        assert!(has_class("<path class=\"a filled\"/>", "filled"));
+        assert!(has_class("<path class='filled'/>", "filled"));
+        assert!(!has_class("<path class=\"unfilled\"/>", "filled"));
+        assert!(!has_class("<style>.filled{fill:black}</style>", "filled"));
+    }
+
+    #[test]
+    fn figure_numbers() {
+        let (output, _) = transform(
+            "",
+            &[
+                "```svgbob,figure",
+                "a-b",
+                "```",
+                "```svgbob",
+                "c-d",
+                "```",
+                "```svgbob,figure",
+                "e-f",
+                "```",
+            ],
+        )
+        .unwrap();
+        let captions: Vec<&str> = output
+            .split("<figcaption")
+            .skip(1)
+            .map(|s| &s[s.find('>').unwrap() + 1..s.find("</figcaption>").unwrap()])
+            .collect();
+        assert_eq!(captions, ["Figure 1.", "Figure 2."]);
+    }
+
+    #[test]
+    fn fence_char() {
+        let lines = [":::svgbob", "a-b", ":::"];
+        let (output, _) = transform("fence-char=:", &lines).unwrap();
+        assert!(output.trim_start().starts_with("![](data:"), "{}", output);
+        assert!(!output.contains(":::"), "{}", output);
+
+        // Backticks and tildes are still recognized
+        let (output, _) = transform("fence-char=:", &["~~~svgbob", "a-b", "~~~"]).unwrap();
+        assert!(output.trim_start().starts_with("![](data:"), "{}", output);
+
+        let (output, _) = transform("", &lines).unwrap();
+        assert_eq!(output.trim_end(), lines.join("\n"));
+    }
+
+    #[test]
+    fn dpi() {
+        let size = |options| {
+            let (output, _) = transform(options, &["```svgbob", "a-b", "```"]).unwrap();
+            let svg_code = decode_image(&output);
+            let [width, height] = [
+                root_attr(&svg_code, "width").unwrap().to_owned(),
+                root_attr(&svg_code, "height").unwrap().to_owned(),
+            ];
+            (
+                width,
+                height,
+                root_attr(&svg_code, "viewBox").map(str::to_owned),
+            )
+        };
+        let (px_width, px_height, _) = size("");
+        let (px_width, px_height): (f32, f32) =
+            (px_width.parse().unwrap(), px_height.parse().unwrap());
+
+        let (width, height, view_box) = size("unit=mm,dpi=48");
+        assert_eq!(width, format!("{:.3}mm", px_width * 25.4 / 48.0));
+        assert_eq!(height, format!("{:.3}mm", px_height * 25.4 / 48.0));
+        // The coordinate system is kept in pixels
+        assert_eq!(view_box.unwrap(), format!("0 0 {} {}", px_width, px_height));
+
+        let (width, _, _) = size("unit=in");
+        assert_eq!(width, format!("{:.3}in", px_width / 96.0));
+
+        assert!(transform("strict-render", &["```svgbob,dpi=0", "a-b", "```"]).is_err());
+    }
+
+    #[test]
+    fn palette() {
+        let mut svg_code =
+            "<path stroke=\"#f00\" fill=\"#888\"/><text style=\"fill:#0F0;\"/><path stroke=\"#F00\"/>"
+                .to_owned();
+        apply_palette(&mut svg_code, &["#111", "#222"]);
+        // Distinct colors are mapped in the order of their first appearance,
+        // and nearly gray colors are kept
+        assert_eq!(
+            svg_code,
+            "<path stroke=\"#111\" fill=\"#888\"/><text style=\"fill:#222;\"/><path stroke=\"#111\"/>"
+        );
+
+        let (output, _) = transform(
+            r#"theme-presets="rgb:#ff0000,#00ff00,#333333""#,
+            &["```svgbob,theme=rgb,palette=colorblind-safe", "a-b", "```"],
+        )
+        .unwrap();
+        let svg_code = decode_image(&output);
+        for rule in &[
+            " polyline{stroke:#0072b2;}",
+            " text{fill:#e69f00;}",
+            "{stroke:none;fill:#333333;}",
+        ] {
+            assert!(svg_code.contains(rule), "{}: {}", rule, svg_code);
+        }
+        assert!(!svg_code.contains("#ff0000"), "{}", svg_code);
+    }
+
+    #[test]
+    fn xml_lang() {
+        for options in &["", "encoding=inline-svg"] {
+            let (output, _) = transform(options, &["```svgbob,xml-lang=fr", "ab", "```"]).unwrap();
+            let svg_code = if options.is_empty() {
+                decode_image(&output)
+            } else {
+                output.clone()
+            };
+            assert_eq!(root_attr(&svg_code, "lang"), Some("fr"), "{}", svg_code);
+            assert_eq!(root_attr(&svg_code, "xml:lang"), Some("fr"), "{}", svg_code);
+        }
+
+        // Neither the hint nor an unknown option sets the language
+        for fence in &["```svgbob,lang:fr", "```svgbob,lang=fr"] {
+            let (output, _) = transform("", &[fence, "ab", "```"]).unwrap();
+            let svg_code = decode_image(&output);
+            assert_eq!(root_attr(&svg_code, "lang"), None, "{}", svg_code);
+        }
+    }
+
+    #[test]
+    fn timings() {
+        let lines = ["```svgbob", "a-b", "```", "text", "```svgbob", "c-d", "```"];
+        let (_, warnings) = transform("timings", &lines).unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].starts_with("svgbob render times of 2 diagram(s), slowest first: diagram "),
+            "{:?}",
+            warnings
+        );
+        for index in &[1, 2] {
+            assert!(
+                warnings[0].contains(&format!("diagram {}: ", index)),
+                "{:?}",
+                warnings
+            );
+        }
+        assert!(warnings[0].ends_with("ms"), "{:?}", warnings);
+
+        let (_, warnings) = transform("", &lines).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn with_source() {
+        let source = "<details class='svgbob-source'><summary>Source</summary>\
+                      <pre>a&lt;-b&#10; c</pre></details>";
+        let (output, _) = transform("with-source", &["```svgbob", "a<-b", " c", "```"]).unwrap();
+        assert!(output.trim_start().starts_with("![](data:"), "{}", output);
+        assert!(
+            output.trim_end().ends_with(&format!("){}", source)),
+            "{}",
+            output
+        );
+
+        // Overridden by the code fence header
+        let (output, _) = transform(
+            "with-source",
+            &["```svgbob,with-source=false", "a<-b", "```"],
+        )
+        .unwrap();
+        assert!(!output.contains("<details"), "{}", output);
+    }
+
+    #[test]
+    fn trailing_prose() {
+        let (output, _) = transform("", &["```svgbob\nab\n```\nMore prose\n"]).unwrap();
+        assert!(output.starts_with("![](data:"), "{}", output);
+        assert!(output.trim_end().ends_with(")\nMore prose"), "{:?}", output);
+
+        let (output, _) =
+            transform("encoding=inline-svg", &["```svgbob\nab\n```\nMore prose\n"]).unwrap();
+        assert!(output.starts_with("<div><svg"), "{}", output);
+        // A blank line ends the HTML block so that the prose is Markdown again
+        assert!(
+            output.trim_end().ends_with("</svg></div>\n\nMore prose"),
+            "{:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn figure_caption_ids() {
+        let lines = [
+            "```svgbob,figure",
+            "a-b",
+            "```",
+            "```svgbob,figure,encoding=inline-svg",
+            "a-b",
+            "```",
+        ];
+        let (output, _) = transform("", &lines).unwrap();
+        let attr_values = |name: &str| -> Vec<String> {
+            output
+                .split(&format!("{}='", name))
+                .skip(1)
+                .map(|s| s[..s.find('\'').unwrap()].to_owned())
+                .collect()
+        };
+        let labels = attr_values("aria-labelledby");
+        assert_eq!(labels.len(), 2, "{}", output);
+        assert!(output.contains(&format!(
+            "<figure role='figure' aria-labelledby='{}'>",
+            labels[0]
+        )));
+        for label in &labels {
+            assert!(
+                output.contains(&format!("<figcaption id='{}'>", label)),
+                "{}: {}",
+                label,
+                output
+            );
+        }
+        assert_ne!(labels[0], labels[1]);
+
+        // Derived from the content, so they don't collide with another doc
+        // comment's
+        let (other, _) = transform("", &["```svgbob,figure", "c-d", "```"]).unwrap();
+        assert!(!other.contains(&labels[0]), "{}", other);
+        assert_eq!(transform("", &lines).unwrap().0, output);
+    }
+
+    #[test]
+    fn into_string() {
+        assert_eq!(
+            TextProcOutput::Passthrough.into_string("a"),
+            Some("a".to_owned())
+        );
+        assert_eq!(TextProcOutput::Empty.into_string("a"), None);
+        assert_eq!(
+            TextProcOutput::Fragment("b".to_owned()).into_string("a"),
+            Some("b".to_owned())
+        );
+    }
+
+    #[test]
+    fn fit_tight() {
+        let art = ["", "   +--+", "   |ab|", "   +--+   ", ""];
+        let view_box = |options: &str| {
+            let lines: Vec<String> = std::iter::once(format!("```svgbob,{}", options))
+                .chain(art.iter().map(|&line| line.to_owned()))
+                .chain(std::iter::once("```".to_owned()))
+                .collect();
+            let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+            let (output, warnings) = transform("", &lines).unwrap();
+            let svg_code = decode_image(&output);
+            let view_box = root_view_box(&svg_code);
+            let width: f32 = root_attr(&svg_code, "width").unwrap().parse().unwrap();
+            assert_eq!(width.to_bits(), view_box[2].to_bits(), "{}", svg_code);
+            (view_box, warnings)
+        };
+        let ([x, y, width, height], _) = view_box("fit=grid");
+        let ([tight_x, tight_y, tight_width, tight_height], warnings) = view_box("fit=tight");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(tight_x > x && tight_y > y, "{:?}", (tight_x, tight_y));
+        assert!(tight_width < width && tight_height < height);
+        // Kept within the grid
+        assert!(tight_x + tight_width <= x + width && tight_y + tight_height <= y + height);
+
+        // Diagrams in a group keep their aligned widths
+        let (output, warnings) = transform(
+            "",
+            &[
+                "```svgbob,group=a,fit=tight",
+                "  ab",
+                "```",
+                "```svgbob,group=a",
+                "abcdef",
+                "```",
+            ],
+        )
+        .unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].contains("`fit=tight` has no effect"),
+            "{:?}",
+            warnings
+        );
+        let svg_code = decode_image(&output);
+        assert_eq!(root_view_box(&svg_code)[0].to_bits(), 0.0f32.to_bits());
+    }
+
+    #[test]
+    fn drawn_bounds() {
+        let svg_code = "<svg><defs><marker><path d=\"M 0 0 L 100 100\"/></marker></defs>\
+                        <style>rect{x:1}</style>\
+                        <rect class=\"backdrop\" x=\"0\" y=\"0\" width=\"200\" height=\"200\"/>\
+                        <line x1=\"10\" y1=\"20\" x2=\"30\" y2=\"20\"/>\
+                        <circle cx=\"50\" cy=\"50\" r=\"5\"/>\
+                        <text x=\"60\" y=\"12\" textLength=\"16\">ab</text>\
+                        <text x=\"0\" y=\"0\"> </text></svg>";
+        assert_eq!(
+            format!("{:?}", super::drawn_bounds(svg_code, 16.0)),
+            "Some([10.0, 0.0, 76.0, 55.0])"
+        );
+        assert_eq!(super::drawn_bounds("<svg></svg>", 16.0), None);
+    }
+
+    #[test]
+    fn path_points() {
+        let mut points = Vec::new();
+        super::path_points(
+            "M 1,2 L3-4 h 5 v-1 A 4,4 0,0,0 20,20 m1 1 l 1 1 Z",
+            &mut |x, y| points.push((x, y)),
+        );
+        assert_eq!(
+            points,
+            [
+                (1.0, 2.0),
+                (3.0, -4.0),
+                (8.0, -4.0),
+                (8.0, -5.0),
+                (20.0, 20.0),
+                (21.0, 21.0),
+                (22.0, 22.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn percent_size() {
+        let (output, _) = transform("encoding=inline-svg", &["```svgbob", "ab", "```"]).unwrap();
+        let (width, height) = (
+            root_attr(&output, "width").unwrap(),
+            root_attr(&output, "height").unwrap(),
+        );
+
+        let (output, _) = transform(
+            "encoding=inline-svg",
+            &["```svgbob,width=100%", "ab", "```"],
+        )
+        .unwrap();
+        assert_eq!(root_attr(&output, "width"), Some("100%"), "{}", output);
+        // The height follows the aspect ratio of the view box
+        assert_eq!(root_attr(&output, "height"), None, "{}", output);
+        assert_eq!(
+            root_attr(&output, "viewBox"),
+            Some(&*format!("0 0 {} {}", width, height)),
+            "{}",
+            output
+        );
+
+        let (output, _) = transform(
+            "encoding=inline-svg",
+            &["```svgbob,width=50%,height=20.5%", "ab", "```"],
+        )
+        .unwrap();
+        assert_eq!(root_attr(&output, "width"), Some("50%"), "{}", output);
+        assert_eq!(root_attr(&output, "height"), Some("20.5%"), "{}", output);
+
+        assert!(transform("strict-render", &["```svgbob,width=100", "ab", "```"]).is_err());
+    }
+
+    #[test]
+    fn defer() {
+        let out_dir = std::env::temp_dir().join("svgbobdoc-test-defer");
+        let options = format!("out-dir={:?}", out_dir.to_str().unwrap());
+        let (output, _) = transform(&options, &["```svgbob,defer", "a-b", "```"]).unwrap();
+        let output = output.trim();
+        let hash = output
+            .strip_prefix("<!-- svgbob-defer: ")
+            .and_then(|marker| marker.strip_suffix(" -->"))
+            .expect(output);
+        assert_eq!(hash.len(), 16, "{}", hash);
+        assert_eq!(
+            std::fs::read_to_string(out_dir.join(format!("{}.txt", hash))).unwrap(),
+            "a-b"
+        );
+
+        let error = transform("strict-render", &["```svgbob,defer", "a-b", "```"]).unwrap_err();
+        assert!(
+            error.to_string().contains("requires `out-dir`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn audit() {
+        let lines = [
+            "```svbgob",
+            "ab",
+            "```",
+            "```SVGBOB,x",
+            "```",
+            "```rust",
+            "```",
+            "```svg",
+            "```",
+            "```svgbob",
+            "ab",
+            "```",
+            "```svgbobs",
+            "```",
+        ];
+        let (_, warnings) = transform("", &lines).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+
+        let (_, warnings) = transform("audit", &lines).unwrap();
+        let languages: Vec<&str> = warnings
+            .iter()
+            .map(|warning| {
+                assert!(
+                    warning.ends_with("looks like a misspelling of `svgbob`"),
+                    "{}",
+                    warning
+                );
+                warning.split('`').nth(1).unwrap()
+            })
+            .collect();
+        assert_eq!(languages, ["svbgob", "SVGBOB", "svgbobs"]);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn group() {
+        let widths = |options: &str, lines: &[&str]| {
+            let (output, _) = transform(options, lines).unwrap();
+            let widths: Vec<_> = output
+                .match_indices("base64,")
+                .map(|(i, _)| root_view_box(&decode_image(&output[i..]))[2].to_bits())
+                .collect();
+            widths
+        };
+        let width = |lines: &[&str]| widths("", lines)[0];
+
+        // The content captured for `src` is ignored
+        let lines = [
+            "```svgbob,group=a,src=tests/fixtures/two-lines.bob",
+            "```",
+            "```svgbob,group=a",
+            "a",
+            "```",
+        ];
+        let expected = width(&["```svgbob", "ab", "```"]);
+        assert_eq!(widths("", &lines), [expected; 2]);
+
+        // So are the blank lines removed by `trim-blank-lines`, which would
+        // make the image higher and so wider with `aspect`
+        let lines = [
+            "```svgbob,group=a,aspect=4:1,trim-blank-lines",
+            "ab",
+            "",
+            "",
+            "```",
+            "```svgbob,group=a",
+            "a",
+            "```",
+        ];
+        let expected = width(&["```svgbob,aspect=4:1", "ab", "```"]);
+        assert_eq!(widths("", &lines), [expected; 2]);
+
+        // The later changes to the size are included
+        for option in &["aspect=4:1", "card"] {
+            let lines = [
+                &format!("```svgbob,group=a,{}", option)[..],
+                "ab",
+                "```",
+                "```svgbob,group=a",
+                "abc",
+                "```",
+            ];
+            let expected = width(&[&format!("```svgbob,{}", option), "ab", "```"]);
+            assert_ne!(expected, width(&["```svgbob", "abc", "```"]));
+            assert_eq!(widths("", &lines), [expected; 2], "{}", option);
+        }
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let lines = ["日本語 ✓\n　```svgbob\né\n```svgbob\nあ-い\n```\n🦀"];
+        let (output, _) = transform("", &lines).unwrap();
+        assert!(
+            output.starts_with("日本語 ✓\n　```svgbob\né\n![](data:"),
+            "{}",
+            output
+        );
+        assert!(output.trim_end().ends_with(")\n🦀"), "{}", output);
+
+        let (output, _) = transform("", &["é", " ```svgbob", "あ", " ```", "🦀"]).unwrap();
+        let image = output.find("![](data:").expect(&output);
+        assert_eq!(output[..image].trim_end(), "é");
+        assert!(output.trim_end().ends_with(")\n🦀"), "{}", output);
+    }
+
+    #[test]
+    fn fragment_prefixes() {
+        assert_eq!(fragment_prefix("é\nあ", 3), "é\n");
+        assert_eq!(fragment_prefix("é\nあ", 0), "");
+    }
+
+    #[test]
+    #[should_panic(expected = "fragment sliced inside a character at byte 1")]
+    fn fragment_prefix_inside_character() {
+        fragment_prefix("é\nあ", 1);
+    }
+
+    #[test]
+    fn shadow() {
+        let lines = ["```svgbob,shadow", "a->b", "```"];
+        let (output, warnings) = transform("encoding=inline-svg", &lines).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert!(
+            output.contains("<filter id=\"svgbob-shadow\""),
+            "{}",
+            output
+        );
+        assert!(output.contains("<feDropShadow "), "{}", output);
+        assert!(output.contains(" rect:not(.backdrop),"), "{}", output);
+        assert!(
+            output.contains("{filter:url(#svgbob-shadow);}"),
+            "{}",
+            output
+        );
+
+        let (output, _) = transform("encoding=inline-svg", &["```svgbob", "a->b", "```"]).unwrap();
+        assert!(!output.contains("svgbob-shadow"), "{}", output);
+    }
+
+    #[cfg(feature = "enable")]
+    #[test]
+    fn shadow_without_backdrop() {
+        let mut options = DiagramOptions::default();
+        options.set_part("shadow").unwrap();
+        let mut warnings = Vec::new();
+        let svg_code = "<svg width=\"16\" height=\"16\"><text>ab</text></svg>".to_owned();
+        let svg_code = apply_svg_options(svg_code, "ab", &options, None, &mut warnings);
+        assert!(svg_code.contains("svgbob-shadow"), "{}", svg_code);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("`shadow`"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn groups_detected() {
+        let fragments = |lines: &[&str]| {
+            lines
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        };
+        let defaults = DiagramOptions::default();
+        assert!(!may_have_groups(
+            &defaults,
+            &fragments(&["```svgbob", "ab", "```"])
+        ));
+        assert!(may_have_groups(
+            &defaults,
+            &fragments(&["```svgbob,group=a", "ab", "```"])
+        ));
+        assert!(may_have_groups(
+            &defaults,
+            &fragments(&["<!-- svgbob: group=a -->"])
+        ));
+
+        let mut defaults = DiagramOptions::default();
+        defaults.set_list("group=a").unwrap();
+        assert!(may_have_groups(
+            &defaults,
+            &fragments(&["```svgbob", "ab", "```"])
+        ));
+    }
 }